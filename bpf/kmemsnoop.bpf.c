@@ -11,18 +11,32 @@
 
 #include "msg.h"
 #include "utils.h"
-
-const volatile u32 bp_type;
-const volatile u64 bp_len;
+#include "watch.h"
 
 struct {
     __uint(type, BPF_MAP_TYPE_RINGBUF);
     __uint(max_entries, 4096);
 } msg_ringbuf SEC(".maps");
 
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, MAX_WATCHES);
+    __type(key, u64);
+    __type(value, watch_t);
+} watch_map SEC(".maps");
+
+/* The number of hits, which is counted apart from the messages
+ * because they can be dropped when the ring buffer is full. */
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, u32);
+    __type(value, u64);
+} hit_count SEC(".maps");
+
 u64 MSG_ID = 0;
 
-static msg_ent_t *get_message(msg_type_t type, u64 timestamp)
+static msg_ent_t *get_message(msg_type_t type, watch_t *watch, u64 timestamp)
 {
     pid_t pid = (bpf_get_current_pid_tgid() >> 32);
     size_t total_size = sizeof(msg_ent_t);
@@ -48,6 +62,8 @@ static msg_ent_t *get_message(msg_type_t type, u64 timestamp)
     ent->id = id;
     ent->type = type;
     ent->pid = pid;
+    ent->watch = watch->id;
+    ent->part = watch->part;
     ent->timestamp = timestamp;
     bpf_get_current_comm(&ent->cmd, sizeof(ent->cmd));
 
@@ -61,12 +77,14 @@ static void submit_message(msg_ent_t *ent)
     bpf_ringbuf_submit(ent, 0);
 }
 
-static void submit_msg_stack(struct bpf_perf_event_data *ctx, u64 timestamp)
+static void submit_msg_stack(struct bpf_perf_event_data *ctx,
+                             watch_t *watch,
+                             u64 timestamp)
 {
     msg_ent_t *ent;
     stack_msg_t *stack_msg;
 
-    ent = get_message(MSG_TYPE_STACK, timestamp);
+    ent = get_message(MSG_TYPE_STACK, watch, timestamp);
     if (!ent)
         return;
 
@@ -78,31 +96,39 @@ static void submit_msg_stack(struct bpf_perf_event_data *ctx, u64 timestamp)
     submit_message(ent);
 }
 
-static void submit_msg_data(struct bpf_perf_event_data *ctx, u64 timestamp)
+static void submit_msg_data(struct bpf_perf_event_data *ctx,
+                            watch_t *watch,
+                            u64 timestamp)
 {
     msg_ent_t *ent;
     data_msg_t *data_msg;
     void *data_ptr = (void *) ctx->addr;
+    u64 len = watch->len;
 
     /* Don't share this type of message if this is an
      * executable point */
-    if (bp_type == HW_BREAKPOINT_X)
+    if (watch->type == HW_BREAKPOINT_X)
         return;
 
-    ent = get_message(MSG_TYPE_DATA, timestamp);
+    /* Keep the verifier happy: we can't read more than the
+     * size of the value. */
+    if (len > sizeof(data_msg->val))
+        len = sizeof(data_msg->val);
+
+    ent = get_message(MSG_TYPE_DATA, watch, timestamp);
     if (!ent)
         return;
 
     data_msg = GET_INNER_MSG(ent, data_msg_t);
 
     data_msg->addr = ctx->addr;
-    /* Zero first: bp_len may be < 8, and a failed read leaves the
+    /* Zero first: len may be < 8, and a failed read leaves the
      * destination untouched, so the unread bytes must not be garbage. */
     data_msg->val = 0;
     if (data_ptr) {
-        long err = bpf_core_read(&data_msg->val, bp_len, data_ptr);
+        long err = bpf_core_read(&data_msg->val, len, data_ptr);
         if (err)
-            bpf_printk("Fail to read %d bytes at %llx: %ld", bp_len, ctx->addr,
+            bpf_printk("Fail to read %d bytes at %llx: %ld", len, ctx->addr,
                        err);
     }
 
@@ -114,9 +140,23 @@ int perf_event_handler(struct bpf_perf_event_data *ctx)
 {
     // Get the event timestamp as soon as possible
     u64 timestamp = bpf_ktime_get_ns();
-
-    submit_msg_stack(ctx, timestamp);
-    submit_msg_data(ctx, timestamp);
+    u64 addr = ctx->addr;
+    u32 key = 0;
+    watch_t *watch;
+    u64 *count;
+
+    /* The sample address of a breakpoint event is the address
+     * of the breakpoint, so we can find which watch is hit. */
+    watch = bpf_map_lookup_elem(&watch_map, &addr);
+    if (!watch)
+        return 0;
+
+    count = bpf_map_lookup_elem(&hit_count, &key);
+    if (count)
+        *count += 1;
+
+    submit_msg_stack(ctx, watch, timestamp);
+    submit_msg_data(ctx, watch, timestamp);
 
     return 0;
 }
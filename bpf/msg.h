@@ -18,6 +18,8 @@ typedef struct {
     u64 type;
     u64 timestamp;
     u64 pid;
+    u64 watch;
+    u64 part;
     char cmd[TASK_COMM_LEN];
 
     u8 inner[0];
@@ -0,0 +1,16 @@
+#ifndef WATCH_H
+#define WATCH_H
+
+#define MAX_WATCHES 1024
+
+/* The attributes of a hardware breakpoint. A watchpoint may be split
+ * into several parts, each of them is a hardware breakpoint. It is
+ * indexed by the address of the breakpoint in watch_map. */
+typedef struct {
+    u32 id;
+    u32 part;
+    u32 type;
+    u32 len;
+} watch_t;
+
+#endif
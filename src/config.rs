@@ -0,0 +1,359 @@
+use crate::kexpr::{parse_pid_fd, KexprRoot};
+use crate::msg::{parse_format, OutputFormat};
+use crate::utils::{parse_cpu_list, parse_duration};
+use crate::watch::*;
+
+use std::fs;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use libc::{c_int, pid_t};
+use serde::Deserialize;
+use toml::Spanned;
+
+/* The layout of the session file, for example:
+ *
+ *   vmlinux = "/path/to/vmlinux"   # or symbols = "/path/to/System.map"
+ *   cpus = "0-3,8"
+ *   duration = "30s"
+ *   format = "json"
+ *
+ *   [[watch]]
+ *   kind = "rw"
+ *   len = 4
+ *   expr = "&on_rq"
+ *   pid-task = 1
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    vmlinux: Option<String>,
+    symbols: Option<Spanned<String>>,
+    task: Option<Spanned<pid_t>>,
+    tgid: Option<Spanned<pid_t>>,
+    cpus: Option<Spanned<String>>,
+    count: Option<u64>,
+    duration: Option<Spanned<String>>,
+    #[serde(default)]
+    exit_on_hit: bool,
+    percpu: Option<Spanned<PerCpuEnt>>,
+    format: Option<Spanned<String>>,
+    #[serde(default)]
+    watch: Vec<Spanned<WatchEnt>>,
+}
+
+/* The CPU is a number or "all" */
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PerCpuEnt {
+    Cpu(c_int),
+    Keyword(String),
+}
+
+/* The length is a number or "auto" */
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LenEnt {
+    Bytes(u64),
+    Keyword(String),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct WatchEnt {
+    kind: Spanned<String>,
+    len: Option<Spanned<LenEnt>>,
+    expr: String,
+    pid_task: Option<u64>,
+    pci_dev: Option<String>,
+    usb_dev: Option<String>,
+    plat_dev: Option<String>,
+    global: Option<String>,
+    file: Option<String>,
+    sock: Option<String>,
+    inode: Option<String>,
+    sb: Option<String>,
+    module: Option<String>,
+    irq: Option<u64>,
+    net_dev: Option<String>,
+    netns_pid: Option<u64>,
+    net_priv: Option<String>,
+    percpu: Option<Spanned<PerCpuEnt>>,
+}
+
+pub struct Config {
+    pub vmlinux: Option<String>,
+    pub symbols: Option<String>,
+    pub task: Option<pid_t>,
+    pub tgid: Option<pid_t>,
+    pub cpus: Option<Vec<c_int>>,
+    pub count: Option<u64>,
+    pub duration: Option<Duration>,
+    pub exit_on_hit: bool,
+    pub percpu: Option<PerCpu>,
+    pub format: Option<OutputFormat>,
+    pub watches: Vec<WatchArg>,
+}
+
+fn line_of(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+fn parse_percpu_ent(content: &str, percpu: &Spanned<PerCpuEnt>) -> Result<PerCpu> {
+    let line = line_of(content, percpu.span().start);
+    match percpu.get_ref() {
+        PerCpuEnt::Cpu(cpu) => {
+            parse_percpu(&cpu.to_string()).map_err(|e| anyhow!("line {line}: {e}"))
+        }
+        PerCpuEnt::Keyword(word) => parse_percpu(word).map_err(|e| anyhow!("line {line}: {e}")),
+    }
+}
+
+fn watch_root(ent: &WatchEnt) -> Result<Option<KexprRoot>, String> {
+    let mut roots = Vec::new();
+    if let Some(pid) = ent.pid_task {
+        roots.push(KexprRoot::Task(pid));
+    }
+    if let Some(dev) = &ent.pci_dev {
+        roots.push(KexprRoot::PciDev(dev.clone()));
+    }
+    if let Some(dev) = &ent.usb_dev {
+        roots.push(KexprRoot::UsbDev(dev.clone()));
+    }
+    if let Some(dev) = &ent.plat_dev {
+        roots.push(KexprRoot::PlatDev(dev.clone()));
+    }
+    if let Some(sym) = &ent.global {
+        roots.push(KexprRoot::Global(sym.clone()));
+    }
+    if let Some(file) = &ent.file {
+        let (pid, fd) = parse_pid_fd(file).map_err(|e| e.to_string())?;
+        roots.push(KexprRoot::File(pid, fd));
+    }
+    if let Some(sock) = &ent.sock {
+        let (pid, fd) = parse_pid_fd(sock).map_err(|e| e.to_string())?;
+        roots.push(KexprRoot::Sock(pid, fd));
+    }
+    if let Some(path) = &ent.inode {
+        roots.push(KexprRoot::Inode(path.clone()));
+    }
+    if let Some(path) = &ent.sb {
+        roots.push(KexprRoot::Sb(path.clone()));
+    }
+    if let Some(name) = &ent.module {
+        roots.push(KexprRoot::Module(name.clone()));
+    }
+    if let Some(irq) = ent.irq {
+        roots.push(KexprRoot::Irq(irq));
+    }
+    if let Some(name) = &ent.net_dev {
+        roots.push(KexprRoot::NetDev {
+            name: name.clone(),
+            netns_pid: ent.netns_pid,
+            priv_type: ent.net_priv.clone(),
+        });
+    } else if ent.netns_pid.is_some() || ent.net_priv.is_some() {
+        return Err("netns-pid and net-priv are only for net-dev".to_string());
+    }
+
+    if roots.len() > 1 {
+        return Err("only one kexpr root can be specified for a watch".to_string());
+    }
+
+    Ok(roots.pop())
+}
+
+fn parse_watch(content: &str, ent: &Spanned<WatchEnt>) -> Result<WatchArg> {
+    let watch = ent.get_ref();
+    let err = |offset: usize, msg: String| anyhow!("line {}: {msg}", line_of(content, offset));
+
+    let kind = &watch.kind;
+    let bp_type = parse_bp_kind(kind.get_ref()).ok_or(err(
+        kind.span().start,
+        format!("invalid kind {}, expect r, w, rw or x", kind.get_ref()),
+    ))?;
+
+    let bp_len = match &watch.len {
+        None => BpLen::Auto,
+        Some(len) => match len.get_ref() {
+            LenEnt::Bytes(0) => return Err(err(len.span().start, "invalid len 0".to_string())),
+            LenEnt::Bytes(len) => BpLen::Bytes(*len),
+            LenEnt::Keyword(word) if word == "auto" => BpLen::Auto,
+            LenEnt::Keyword(word) => {
+                return Err(err(len.span().start, format!("invalid len {word}")))
+            }
+        },
+    };
+
+    let root = watch_root(watch).map_err(|msg| err(ent.span().start, msg))?;
+
+    let percpu = match &watch.percpu {
+        Some(percpu) => Some(parse_percpu_ent(content, percpu)?),
+        None => None,
+    };
+
+    Ok(WatchArg {
+        bp_type,
+        bp_len,
+        expr: watch.expr.clone(),
+        root,
+        percpu,
+    })
+}
+
+impl Config {
+    pub fn parse(content: &str) -> Result<Self> {
+        let file: ConfigFile = toml::from_str(content)?;
+
+        if let (Some(_), Some(tgid)) = (&file.task, &file.tgid) {
+            return Err(anyhow!(
+                "line {}: task and tgid can't be specified together",
+                line_of(content, tgid.span().start)
+            ));
+        }
+
+        let mut cpus = None;
+        if let Some(list) = &file.cpus {
+            let line = line_of(content, list.span().start);
+            if file.task.is_some() || file.tgid.is_some() {
+                return Err(anyhow!(
+                    "line {line}: cpus can't be specified with task or tgid"
+                ));
+            }
+            let list = parse_cpu_list(list.get_ref()).map_err(|e| anyhow!("line {line}: {e}"))?;
+            cpus = Some(list);
+        }
+
+        if let (Some(_), Some(symbols)) = (&file.vmlinux, &file.symbols) {
+            let line = line_of(content, symbols.span().start);
+            return Err(anyhow!(
+                "line {line}: symbols can't be specified with vmlinux"
+            ));
+        }
+
+        let mut duration = None;
+        if let Some(time) = &file.duration {
+            let line = line_of(content, time.span().start);
+            let time = parse_duration(time.get_ref()).map_err(|e| anyhow!("line {line}: {e}"))?;
+            duration = Some(time);
+        }
+
+        let mut percpu = None;
+        if let Some(ent) = &file.percpu {
+            percpu = Some(parse_percpu_ent(content, ent)?);
+        }
+
+        let mut format = None;
+        if let Some(ent) = &file.format {
+            let line = line_of(content, ent.span().start);
+            format = Some(parse_format(ent.get_ref()).map_err(|e| anyhow!("line {line}: {e}"))?);
+        }
+
+        let mut watches = Vec::new();
+        for ent in &file.watch {
+            watches.push(parse_watch(content, ent)?);
+        }
+
+        Ok(Config {
+            vmlinux: file.vmlinux,
+            symbols: file.symbols.map(|s| s.into_inner()),
+            task: file.task.map(|t| t.into_inner()),
+            tgid: file.tgid.map(|t| t.into_inner()),
+            cpus,
+            count: file.count,
+            duration,
+            exit_on_hit: file.exit_on_hit,
+            percpu,
+            format,
+            watches,
+        })
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).map_err(|e| anyhow!("Failed to read config {path}: {e}"))?;
+        Self::parse(&content).map_err(|e| anyhow!("Invalid config {path}: {e}"))
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+    use perf_event_open_sys::bindings::{HW_BREAKPOINT_RW, HW_BREAKPOINT_X};
+
+    fn parse_err(content: &str) -> String {
+        match Config::parse(content) {
+            Ok(_) => panic!("Expect error for config:\n{content}"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        let config = Config::parse(
+            r#"
+vmlinux = "/path/to/vmlinux"
+cpus = "0-3,8"
+duration = "30s"
+count = 10
+format = "json"
+
+[[watch]]
+kind = "rw"
+len = 4
+expr = "&on_rq"
+pid-task = 1
+
+[[watch]]
+kind = "x"
+expr = "scheduler_tick"
+"#,
+        )?;
+
+        assert_eq!(config.vmlinux.as_deref(), Some("/path/to/vmlinux"));
+        assert_eq!(config.cpus, Some(vec![0, 1, 2, 3, 8]));
+        assert_eq!(config.duration, Some(Duration::from_secs(30)));
+        assert_eq!(config.count, Some(10));
+        assert!(config.format == Some(OutputFormat::Json));
+        assert_eq!(config.watches.len(), 2);
+
+        let watch = &config.watches[0];
+        assert_eq!(watch.bp_type, HW_BREAKPOINT_RW);
+        assert!(watch.bp_len == BpLen::Bytes(4));
+        assert_eq!(watch.expr, "&on_rq");
+        assert!(matches!(watch.root, Some(KexprRoot::Task(1))));
+
+        let watch = &config.watches[1];
+        assert_eq!(watch.bp_type, HW_BREAKPOINT_X);
+        assert!(watch.bp_len == BpLen::Auto);
+        assert!(watch.root.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_error() {
+        let err = parse_err("vmlinux = \"a\"\ncolor = true\n");
+        assert!(err.contains("unknown field `color`"), "{err}");
+        assert!(err.contains("line 2"), "{err}");
+
+        let err = parse_err(
+            "[[watch]]\nkind = \"rw\"\nexpr = \"a\"\n\n[[watch]]\nkind = \"rx\"\nexpr = \"b\"\n",
+        );
+        assert!(err.starts_with("line 6: invalid kind rx"), "{err}");
+
+        let err = parse_err("[[watch]]\nkind = \"rw\"\nlen = 0\nexpr = \"a\"\n");
+        assert!(err.starts_with("line 3: invalid len 0"), "{err}");
+
+        let err = parse_err("count = 1\nformat = \"yaml\"\n");
+        assert!(err.starts_with("line 2: Invalid format yaml"), "{err}");
+
+        let err = parse_err("task = 1\ntgid = 2\n");
+        assert!(err.starts_with("line 2: task and tgid"), "{err}");
+
+        let err = parse_err(
+            "count = 1\n\n[[watch]]\nkind = \"rw\"\nexpr = \"a\"\npid-task = 1\nglobal = \"b\"\n",
+        );
+        assert!(err.starts_with("line 3: only one kexpr root"), "{err}");
+    }
+}
@@ -0,0 +1,70 @@
+use crate::utils::get_online_cpus;
+
+use anyhow::Result;
+use libc::c_int;
+
+/* Track the online CPUs which are selected to be watched, so the
+ * breakpoints can be armed or released when the CPU is hotplugged. */
+pub struct CpuTracker {
+    /* The CPUs selected by user, or None for all the CPUs */
+    filter: Option<Vec<c_int>>,
+    online: Vec<c_int>,
+}
+
+impl CpuTracker {
+    pub fn new(filter: Option<Vec<c_int>>) -> Result<Self> {
+        let mut tracker = CpuTracker {
+            filter,
+            online: Vec::new(),
+        };
+        tracker.online = tracker.selected_online_cpus()?;
+
+        Ok(tracker)
+    }
+
+    fn selected_online_cpus(&self) -> Result<Vec<c_int>> {
+        let mut cpus = get_online_cpus()?;
+        if let Some(filter) = &self.filter {
+            cpus.retain(|cpu| filter.contains(cpu));
+        }
+
+        Ok(cpus)
+    }
+
+    pub fn cpus(&self) -> &[c_int] {
+        &self.online
+    }
+
+    /* The selected CPUs which are not online now */
+    pub fn offline_cpus(&self) -> Vec<c_int> {
+        match &self.filter {
+            Some(filter) => filter
+                .iter()
+                .copied()
+                .filter(|cpu| !self.online.contains(cpu))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /* Return the CPUs which become online and offline since the last
+     * update. */
+    pub fn update(&mut self) -> Result<(Vec<c_int>, Vec<c_int>)> {
+        let online = self.selected_online_cpus()?;
+
+        let up = online
+            .iter()
+            .copied()
+            .filter(|cpu| !self.online.contains(cpu))
+            .collect();
+        let down = self
+            .online
+            .iter()
+            .copied()
+            .filter(|cpu| !online.contains(cpu))
+            .collect();
+
+        self.online = online;
+        Ok((up, down))
+    }
+}
@@ -0,0 +1,491 @@
+use std::fs;
+use std::mem::size_of;
+use std::rc::Rc;
+
+use anyhow::{anyhow, Result};
+
+/* The BTF of the running kernel. The BTF of modules are in the same
+ * directory, which are split BTF based on vmlinux. */
+pub const BTF_DIR: &str = "/sys/kernel/btf";
+const BTF_MAGIC: u16 = 0xeb9f;
+const BTF_HDR_LEN: usize = 24;
+const BTF_TYPE_LEN: usize = 12;
+
+/* enum btf_kind */
+const BTF_KIND_INT: u32 = 1;
+const BTF_KIND_PTR: u32 = 2;
+const BTF_KIND_ARRAY: u32 = 3;
+const BTF_KIND_STRUCT: u32 = 4;
+const BTF_KIND_UNION: u32 = 5;
+const BTF_KIND_ENUM: u32 = 6;
+const BTF_KIND_FWD: u32 = 7;
+const BTF_KIND_TYPEDEF: u32 = 8;
+const BTF_KIND_VOLATILE: u32 = 9;
+const BTF_KIND_CONST: u32 = 10;
+const BTF_KIND_RESTRICT: u32 = 11;
+const BTF_KIND_FUNC: u32 = 12;
+const BTF_KIND_FUNC_PROTO: u32 = 13;
+const BTF_KIND_VAR: u32 = 14;
+const BTF_KIND_DATASEC: u32 = 15;
+const BTF_KIND_FLOAT: u32 = 16;
+const BTF_KIND_DECL_TAG: u32 = 17;
+const BTF_KIND_TYPE_TAG: u32 = 18;
+const BTF_KIND_ENUM64: u32 = 19;
+
+pub struct BtfMember {
+    pub name: String,
+    pub typ: u32,
+    pub bit_offset: u32,
+    pub bitfield: bool,
+}
+
+pub enum BtfKind {
+    Void,
+    /* The types which have their own size, e.g. int, enum and float */
+    Sized,
+    Ptr(u32),
+    Array(u32, u32),
+    Struct(Vec<BtfMember>),
+    Union(Vec<BtfMember>),
+    Fwd,
+    Typedef(u32),
+    /* The qualifier which doesn't change the type, e.g. const */
+    Modifier(&'static str, u32),
+    Var(u32),
+    /* The types which can't be the type of object, e.g. function */
+    Other,
+}
+
+pub struct BtfType {
+    pub name: String,
+    pub size: u32,
+    pub kind: BtfKind,
+}
+
+/* The types of BTF, where the type id is the index. For the split
+ * BTF of module, the ids and the strings continue from the base. */
+pub struct Btf {
+    base: Option<Rc<Btf>>,
+    start_id: u32,
+    start_str: u32,
+    strs: Vec<u8>,
+    types: Vec<BtfType>,
+}
+
+fn read_u16(data: &[u8], off: usize) -> Result<u16> {
+    data.get(off..off + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(anyhow!("Truncated BTF at {off}"))
+}
+
+fn read_u32(data: &[u8], off: usize) -> Result<u32> {
+    data.get(off..off + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(anyhow!("Truncated BTF at {off}"))
+}
+
+impl Btf {
+    pub fn load() -> Result<Rc<Btf>> {
+        Btf::from_file(&format!("{BTF_DIR}/vmlinux"), None)
+    }
+
+    pub fn from_file(path: &str, base: Option<Rc<Btf>>) -> Result<Rc<Btf>> {
+        let data = fs::read(path).map_err(|e| anyhow!("Failed to read BTF {path}: {e}"))?;
+        let btf = Btf::parse(&data, base).map_err(|e| anyhow!("Invalid BTF {path}: {e}"))?;
+        Ok(Rc::new(btf))
+    }
+
+    pub fn parse(data: &[u8], base: Option<Rc<Btf>>) -> Result<Btf> {
+        if read_u16(data, 0)? != BTF_MAGIC {
+            return Err(anyhow!("Bad magic, only little endian BTF is supported"));
+        }
+        let hdr_len = read_u32(data, 4)? as usize;
+        let type_off = hdr_len + read_u32(data, 8)? as usize;
+        let type_len = read_u32(data, 12)? as usize;
+        let str_off = hdr_len + read_u32(data, 16)? as usize;
+        let str_len = read_u32(data, 20)? as usize;
+        if hdr_len < BTF_HDR_LEN {
+            return Err(anyhow!("Bad header length {hdr_len}"));
+        }
+
+        let strs = data
+            .get(str_off..str_off + str_len)
+            .ok_or(anyhow!("Truncated string section"))?
+            .to_vec();
+        let (start_id, start_str, types) = match &base {
+            Some(base) => (base.nr_types(), base.nr_strs(), Vec::new()),
+            /* The type id 0 is void */
+            None => (
+                0,
+                0,
+                vec![BtfType {
+                    name: "void".to_string(),
+                    size: 0,
+                    kind: BtfKind::Void,
+                }],
+            ),
+        };
+
+        let mut btf = Btf {
+            base,
+            start_id,
+            start_str,
+            strs,
+            types,
+        };
+
+        let mut off = type_off;
+        let end = type_off + type_len;
+        while off < end {
+            let name = btf.str_at(read_u32(data, off)?)?;
+            let info = read_u32(data, off + 4)?;
+            let size = read_u32(data, off + 8)?;
+            let vlen = (info & 0xffff) as usize;
+            let kind_flag = info >> 31 == 1;
+            off += BTF_TYPE_LEN;
+
+            let kind = match (info >> 24) & 0x1f {
+                BTF_KIND_INT => {
+                    off += 4;
+                    BtfKind::Sized
+                }
+                BTF_KIND_ENUM => {
+                    off += 8 * vlen;
+                    BtfKind::Sized
+                }
+                BTF_KIND_ENUM64 => {
+                    off += 12 * vlen;
+                    BtfKind::Sized
+                }
+                BTF_KIND_FLOAT => BtfKind::Sized,
+                BTF_KIND_PTR => BtfKind::Ptr(size),
+                BTF_KIND_ARRAY => {
+                    let kind = BtfKind::Array(read_u32(data, off)?, read_u32(data, off + 8)?);
+                    off += 12;
+                    kind
+                }
+                BTF_KIND_STRUCT => {
+                    let members = btf.parse_members(data, off, vlen, kind_flag)?;
+                    off += 12 * vlen;
+                    BtfKind::Struct(members)
+                }
+                BTF_KIND_UNION => {
+                    let members = btf.parse_members(data, off, vlen, kind_flag)?;
+                    off += 12 * vlen;
+                    BtfKind::Union(members)
+                }
+                BTF_KIND_FWD => BtfKind::Fwd,
+                BTF_KIND_TYPEDEF => BtfKind::Typedef(size),
+                BTF_KIND_VOLATILE => BtfKind::Modifier("volatile", size),
+                BTF_KIND_CONST => BtfKind::Modifier("const", size),
+                BTF_KIND_RESTRICT => BtfKind::Modifier("restrict", size),
+                BTF_KIND_TYPE_TAG => BtfKind::Modifier("", size),
+                BTF_KIND_VAR => {
+                    off += 4;
+                    BtfKind::Var(size)
+                }
+                BTF_KIND_FUNC => BtfKind::Other,
+                BTF_KIND_FUNC_PROTO => {
+                    off += 8 * vlen;
+                    BtfKind::Other
+                }
+                BTF_KIND_DATASEC => {
+                    off += 12 * vlen;
+                    BtfKind::Other
+                }
+                BTF_KIND_DECL_TAG => {
+                    off += 4;
+                    BtfKind::Other
+                }
+                kind => return Err(anyhow!("Unknown kind {kind} at {off}")),
+            };
+
+            btf.types.push(BtfType { name, size, kind });
+        }
+
+        Ok(btf)
+    }
+
+    fn parse_members(
+        &self,
+        data: &[u8],
+        off: usize,
+        vlen: usize,
+        kind_flag: bool,
+    ) -> Result<Vec<BtfMember>> {
+        let mut members = Vec::new();
+        for idx in 0..vlen {
+            let off = off + 12 * idx;
+            let offset = read_u32(data, off + 8)?;
+            /* The size of bitfield is in the upper bits if kind_flag */
+            let (bit_offset, bitfield) = match kind_flag {
+                true => (offset & 0xffffff, offset >> 24 != 0),
+                false => (offset, offset % 8 != 0),
+            };
+            members.push(BtfMember {
+                name: self.str_at(read_u32(data, off)?)?,
+                typ: read_u32(data, off + 4)?,
+                bit_offset,
+                bitfield,
+            });
+        }
+        Ok(members)
+    }
+
+    fn nr_types(&self) -> u32 {
+        self.start_id + self.types.len() as u32
+    }
+
+    fn nr_strs(&self) -> u32 {
+        self.start_str + self.strs.len() as u32
+    }
+
+    fn str_at(&self, off: u32) -> Result<String> {
+        if off < self.start_str {
+            if let Some(base) = &self.base {
+                return base.str_at(off);
+            }
+        }
+
+        let strs = self
+            .strs
+            .get((off - self.start_str) as usize..)
+            .ok_or(anyhow!("Bad string offset {off}"))?;
+        let len = strs.iter().position(|&c| c == 0).unwrap_or(strs.len());
+        Ok(String::from_utf8_lossy(&strs[..len]).into_owned())
+    }
+
+    pub fn ty(&self, id: u32) -> Option<&BtfType> {
+        if id < self.start_id {
+            return self.base.as_ref().and_then(|base| base.ty(id));
+        }
+        self.types.get((id - self.start_id) as usize)
+    }
+
+    fn find(&self, pred: &dyn Fn(&BtfType) -> bool) -> Option<u32> {
+        let own = self.types.iter().position(pred);
+        match own {
+            Some(idx) => Some(self.start_id + idx as u32),
+            None => self.base.as_ref().and_then(|base| base.find(pred)),
+        }
+    }
+
+    /* Find the type by the name in C, e.g. "struct task_struct", "u64"
+     * or "unsigned int". */
+    pub fn find_type(&self, name: &str) -> Option<u32> {
+        let words = name.split_whitespace().collect::<Vec<_>>();
+        match words[..] {
+            ["struct", tag] => {
+                self.find(&|t| t.name == tag && matches!(t.kind, BtfKind::Struct(_)))
+            }
+            ["union", tag] => self.find(&|t| t.name == tag && matches!(t.kind, BtfKind::Union(_))),
+            ["enum", tag] => self.find(&|t| t.name == tag && matches!(t.kind, BtfKind::Sized)),
+            ["void"] => Some(0),
+            _ => {
+                let name = words.join(" ");
+                self.find(&|t| {
+                    t.name == name && matches!(t.kind, BtfKind::Sized | BtfKind::Typedef(_))
+                })
+            }
+        }
+    }
+
+    /* Find the type of global variable. Only the per-CPU variables are
+     * in BTF unless the kernel is built with all the variables. */
+    pub fn find_var(&self, name: &str) -> Option<u32> {
+        let var = self.find(&|t| t.name == name && matches!(t.kind, BtfKind::Var(_)))?;
+        match self.ty(var)?.kind {
+            BtfKind::Var(typ) => Some(typ),
+            _ => None,
+        }
+    }
+
+    /* Skip the typedef and qualifiers to the real type */
+    pub fn resolve(&self, mut id: u32) -> u32 {
+        while let Some(BtfType {
+            kind: BtfKind::Typedef(typ) | BtfKind::Modifier(_, typ),
+            ..
+        }) = self.ty(id)
+        {
+            id = *typ;
+        }
+        id
+    }
+
+    pub fn size_of(&self, id: u32) -> Option<u64> {
+        let typ = self.ty(self.resolve(id))?;
+        match typ.kind {
+            BtfKind::Sized | BtfKind::Struct(_) | BtfKind::Union(_) => Some(typ.size as u64),
+            BtfKind::Ptr(_) => Some(size_of::<usize>() as u64),
+            BtfKind::Array(elem, nelems) => self.size_of(elem).map(|size| size * nelems as u64),
+            BtfKind::Var(typ) => self.size_of(typ),
+            _ => None,
+        }
+    }
+
+    /* Find the member of struct or union, including the members of the
+     * anonymous struct or union in it. Return the member with the bit
+     * offset from the start of the struct. */
+    pub fn find_member(&self, id: u32, name: &str) -> Option<BtfMember> {
+        let members = match &self.ty(self.resolve(id))?.kind {
+            BtfKind::Struct(members) | BtfKind::Union(members) => members,
+            _ => return None,
+        };
+
+        for member in members {
+            if member.name == name {
+                return Some(BtfMember {
+                    name: member.name.clone(),
+                    ..*member
+                });
+            }
+
+            if member.name.is_empty() {
+                if let Some(inner) = self.find_member(member.typ, name) {
+                    return Some(BtfMember {
+                        bit_offset: member.bit_offset + inner.bit_offset,
+                        ..inner
+                    });
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn type_name(&self, id: u32) -> String {
+        let Some(typ) = self.ty(id) else {
+            return format!("<type {id}>");
+        };
+
+        let tag = if typ.name.is_empty() {
+            "<anon>"
+        } else {
+            &typ.name
+        };
+        match typ.kind {
+            BtfKind::Struct(_) => format!("struct {tag}"),
+            BtfKind::Union(_) => format!("union {tag}"),
+            BtfKind::Fwd => format!("struct {tag}"),
+            BtfKind::Ptr(target) => format!("{} *", self.type_name(target)),
+            BtfKind::Array(elem, nelems) => format!("{}[{nelems}]", self.type_name(elem)),
+            BtfKind::Modifier("", typ) => self.type_name(typ),
+            BtfKind::Modifier(qual, typ) => format!("{qual} {}", self.type_name(typ)),
+            _ => tag.to_string(),
+        }
+    }
+}
+
+/* The type of (name, kind, size or type, extra data, members) for
+ * build_btf(), where the member is (name, type, bit offset). */
+#[cfg(test)]
+pub type TestType<'a> = (&'a str, u32, u32, Vec<u32>, Vec<(&'a str, u32, u32)>);
+
+/* Build the BTF from the types, whose ids start from 1 */
+#[cfg(test)]
+pub fn build_btf(types: &[TestType]) -> Vec<u8> {
+    let mut strs = vec![0u8];
+    let mut name_off = |name: &str| {
+        if name.is_empty() {
+            return 0;
+        }
+        let off = strs.len() as u32;
+        strs.extend_from_slice(name.as_bytes());
+        strs.push(0);
+        off
+    };
+
+    let mut vals = Vec::new();
+    for (name, kind, size, extra, members) in types {
+        vals.extend([name_off(name), kind << 24 | members.len() as u32, *size]);
+        vals.extend(extra);
+        for (name, typ, bit_offset) in members {
+            vals.extend([name_off(name), *typ, *bit_offset]);
+        }
+    }
+
+    let type_len = vals.len() * 4;
+    let mut data = Vec::new();
+    data.extend_from_slice(&BTF_MAGIC.to_le_bytes());
+    data.extend_from_slice(&[1, 0]);
+    for val in [BTF_HDR_LEN, 0, type_len, type_len, strs.len()] {
+        data.extend_from_slice(&(val as u32).to_le_bytes());
+    }
+    for val in vals {
+        data.extend_from_slice(&val.to_le_bytes());
+    }
+    data.extend_from_slice(&strs);
+    data
+}
+
+#[cfg(test)]
+mod btf_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_btf() -> Result<()> {
+        let btf = Btf::parse(
+            &build_btf(&[
+                /* 1: int, 2: int *, 3: int[4] */
+                ("int", BTF_KIND_INT, 4, vec![32], vec![]),
+                ("", BTF_KIND_PTR, 1, vec![], vec![]),
+                ("", BTF_KIND_ARRAY, 0, vec![1, 1, 4], vec![]),
+                /* 4: struct foo { int a; union { int *b; int c[4]; }; } */
+                (
+                    "foo",
+                    BTF_KIND_STRUCT,
+                    24,
+                    vec![],
+                    vec![("a", 1, 0), ("", 5, 64)],
+                ),
+                (
+                    "",
+                    BTF_KIND_UNION,
+                    16,
+                    vec![],
+                    vec![("b", 2, 0), ("c", 3, 0)],
+                ),
+                /* 6: const struct foo, 7: typedef foo_t, 8: foo_t bar */
+                ("", BTF_KIND_CONST, 4, vec![], vec![]),
+                ("foo_t", BTF_KIND_TYPEDEF, 6, vec![], vec![]),
+                ("bar", BTF_KIND_VAR, 7, vec![1], vec![]),
+            ]),
+            None,
+        )?;
+
+        assert_eq!(btf.find_type("struct foo"), Some(4));
+        assert_eq!(btf.find_type("int"), Some(1));
+        assert_eq!(btf.find_type("foo_t"), Some(7));
+        assert_eq!(btf.find_type("union foo"), None);
+        assert_eq!(btf.find_var("bar"), Some(7));
+        assert_eq!(btf.resolve(7), 4);
+
+        assert_eq!(btf.size_of(2), Some(size_of::<usize>() as u64));
+        assert_eq!(btf.size_of(3), Some(16));
+        assert_eq!(btf.size_of(7), Some(24));
+        assert_eq!(btf.size_of(0), None);
+
+        let member = btf.find_member(7, "c").unwrap();
+        assert_eq!((member.typ, member.bit_offset), (3, 64));
+        assert!(btf.find_member(7, "d").is_none());
+
+        assert_eq!(btf.type_name(2), "int *");
+        assert_eq!(btf.type_name(3), "int[4]");
+        assert_eq!(btf.type_name(6), "const struct foo");
+        assert_eq!(btf.type_name(5), "union <anon>");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vmlinux_btf() -> Result<()> {
+        let btf = Btf::load()?;
+        let task = btf.find_type("struct task_struct").unwrap();
+        let member = btf.find_member(task, "on_rq").unwrap();
+        assert_eq!(btf.type_name(member.typ), "int");
+        assert_eq!(btf.size_of(member.typ), Some(4));
+        assert!(btf.find_var("runqueues").is_some());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,411 @@
+use std::fs;
+use std::mem::size_of;
+use std::rc::Rc;
+
+use anyhow::{anyhow, Result};
+
+use crate::kexpr::btf::*;
+use crate::kexpr::kcore::*;
+use crate::kexpr::parser::*;
+use crate::kexpr::KexprValue;
+
+/* The type of value in kexpr. The pointer which is made by '&' may
+ * not be in BTF, so it is built on the type of BTF. */
+#[derive(Clone)]
+enum Typ {
+    Btf(u32),
+    Ptr(Box<Typ>),
+}
+
+/* The value is the object in kernel memory, or the number which is
+ * the result of '&' or the cast. */
+#[derive(Clone, Copy)]
+enum Loc {
+    Mem(u64),
+    Num(u64),
+}
+
+pub struct Value {
+    btf: Rc<Btf>,
+    typ: Typ,
+    loc: Loc,
+}
+
+impl Value {
+    fn with(&self, typ: Typ, loc: Loc) -> Value {
+        Value {
+            btf: self.btf.clone(),
+            typ,
+            loc,
+        }
+    }
+
+    fn type_name(&self) -> String {
+        fn name(btf: &Btf, typ: &Typ) -> String {
+            match typ {
+                Typ::Btf(id) => btf.type_name(*id),
+                Typ::Ptr(typ) => format!("{} *", name(btf, typ)),
+            }
+        }
+        name(&self.btf, &self.typ)
+    }
+
+    fn kind(&self) -> Option<&BtfKind> {
+        match self.typ {
+            Typ::Btf(id) => self.btf.ty(self.btf.resolve(id)).map(|typ| &typ.kind),
+            Typ::Ptr(_) => None,
+        }
+    }
+
+    fn size(&self, typ: &Typ) -> Option<u64> {
+        match typ {
+            Typ::Btf(id) => self.btf.size_of(*id),
+            Typ::Ptr(_) => Some(size_of::<usize>() as u64),
+        }
+    }
+
+    /* The type which the pointer points to */
+    fn target(&self) -> Option<Typ> {
+        match (&self.typ, self.kind()) {
+            (Typ::Ptr(typ), _) => Some(*typ.clone()),
+            (_, Some(BtfKind::Ptr(typ))) => Some(Typ::Btf(*typ)),
+            _ => None,
+        }
+    }
+
+    fn is_record(&self) -> bool {
+        matches!(
+            self.kind(),
+            Some(BtfKind::Struct(_) | BtfKind::Union(_) | BtfKind::Array(..))
+        )
+    }
+}
+
+/* The kernel memory with the types from BTF, where kexpr is evaluated
+ * on. The root objects are found by drgn-knight, and only their
+ * addresses are taken here. */
+pub struct Kmem {
+    btf: Rc<Btf>,
+    kcore: Kcore,
+}
+
+impl Kmem {
+    pub fn new() -> Result<Kmem> {
+        Ok(Kmem {
+            btf: Btf::load()?,
+            kcore: Kcore::open(KCORE)?,
+        })
+    }
+
+    /* Find the type in vmlinux, or in the modules if not found */
+    fn find_type(&self, typ: &str) -> Result<(Rc<Btf>, u32)> {
+        if let Some(id) = self.btf.find_type(typ) {
+            return Ok((self.btf.clone(), id));
+        }
+
+        for ent in fs::read_dir(BTF_DIR)? {
+            let path = ent?.path();
+            if path.file_name().is_some_and(|name| name == "vmlinux") {
+                continue;
+            }
+
+            let btf = Btf::from_file(&path.to_string_lossy(), Some(self.btf.clone()))?;
+            if let Some(id) = btf.find_type(typ) {
+                return Ok((btf, id));
+            }
+        }
+
+        Err(anyhow!("Fail to find type {typ} in BTF"))
+    }
+
+    /* The pointer of type to the address, which is the root of kexpr */
+    pub fn pointer(&self, typ: &str, addr: u64) -> Result<Value> {
+        let (btf, id) = self.find_type(typ)?;
+        Ok(Value {
+            btf,
+            typ: Typ::Ptr(Box::new(Typ::Btf(id))),
+            loc: Loc::Num(addr),
+        })
+    }
+
+    /* The pointer to the global variable, if its type is in BTF */
+    pub fn var_pointer(&self, sym: &str, addr: u64) -> Option<Value> {
+        let id = self.btf.find_var(sym)?;
+        Some(Value {
+            btf: self.btf.clone(),
+            typ: Typ::Ptr(Box::new(Typ::Btf(id))),
+            loc: Loc::Num(addr),
+        })
+    }
+
+    pub fn size_of(&self, typ: &str) -> Result<u64> {
+        let (btf, id) = self.find_type(typ)?;
+        btf.size_of(id).ok_or(anyhow!("Fail to get size of {typ}"))
+    }
+
+    pub fn read_u64(&self, addr: u64) -> Result<u64> {
+        self.kcore.read_num(addr, 8)
+    }
+
+    /* Read the value as a number, which is a pointer or an integer */
+    fn load(&self, value: &Value) -> Result<u64> {
+        match value.loc {
+            Loc::Num(num) => Ok(num),
+            Loc::Mem(_) if value.is_record() => Err(anyhow!("Can't read {}", value.type_name())),
+            Loc::Mem(addr) => {
+                let size = value
+                    .size(&value.typ)
+                    .ok_or(anyhow!("Can't read {}", value.type_name()))?;
+                self.kcore.read_num(addr, size)
+            }
+        }
+    }
+
+    fn deref(&self, value: &Value, col: usize) -> Result<Value> {
+        match (value.target(), value.kind(), value.loc) {
+            (Some(typ), _, _) => Ok(value.with(typ, Loc::Mem(self.load(value)?))),
+            /* The array is the pointer to its first element */
+            (_, Some(&BtfKind::Array(elem, _)), Loc::Mem(addr)) => {
+                Ok(value.with(Typ::Btf(elem), Loc::Mem(addr)))
+            }
+            _ => Err(anyhow!(
+                "Can't dereference {} at column {col}",
+                value.type_name()
+            )),
+        }
+    }
+
+    fn access(&self, value: &Value, member: &str, col: usize) -> Result<Value> {
+        let no_member = || {
+            anyhow!(
+                "{} has no member {member} at column {col}",
+                value.type_name()
+            )
+        };
+
+        let (Typ::Btf(id), Loc::Mem(addr)) = (&value.typ, value.loc) else {
+            return Err(no_member());
+        };
+        let field = value.btf.find_member(*id, member).ok_or_else(no_member)?;
+        if field.bitfield {
+            return Err(anyhow!(
+                "{member} of {} is a bitfield at column {col}",
+                value.type_name()
+            ));
+        }
+
+        let addr = addr + (field.bit_offset / 8) as u64;
+        Ok(value.with(Typ::Btf(field.typ), Loc::Mem(addr)))
+    }
+
+    fn subscript(&self, value: &Value, idx: u64, col: usize) -> Result<Value> {
+        let err = || anyhow!("Can't index {} by {idx} at column {col}", value.type_name());
+
+        let is_array = matches!(value.kind(), Some(BtfKind::Array(..)));
+        if value.target().is_none() && !is_array {
+            return Err(err());
+        }
+
+        let elem = self.deref(value, col)?;
+        let size = elem.size(&elem.typ).ok_or_else(err)?;
+        match elem.loc {
+            Loc::Mem(addr) => Ok(Value {
+                loc: Loc::Mem(addr + idx * size),
+                ..elem
+            }),
+            Loc::Num(_) => Err(err()),
+        }
+    }
+
+    /* Cast the pointer or the integer to the type, e.g. "struct foo *" */
+    fn cast(&self, value: &Value, typ: &str, col: usize) -> Result<Value> {
+        let base = typ.trim_end_matches(['*', ' ']);
+        let mut cast = match value.btf.find_type(base) {
+            Some(id) => Typ::Btf(id),
+            None => return Err(anyhow!("Unknown type {base} at column {col}")),
+        };
+        for _ in 0..typ.matches('*').count() {
+            cast = Typ::Ptr(Box::new(cast));
+        }
+
+        let cast = value.with(cast, Loc::Num(0));
+        let num = match (value.kind(), value.loc) {
+            (Some(BtfKind::Array(..)), Loc::Mem(addr)) => addr,
+            _ if value.is_record() || cast.is_record() => {
+                return Err(anyhow!(
+                    "Can't cast {} to {typ} at column {col}",
+                    value.type_name()
+                ))
+            }
+            _ => self.load(value)?,
+        };
+
+        Ok(Value {
+            loc: Loc::Num(num),
+            ..cast
+        })
+    }
+
+    fn eval(&self, root: &Value, kexpr: &Kexpr) -> Result<Value> {
+        let col = kexpr.col;
+        match &kexpr.op {
+            KexprOp::Member(member) => self.access(&self.deref(root, col)?, member, col),
+            KexprOp::Access(expr, member) => self.access(&self.eval(root, expr)?, member, col),
+            KexprOp::PtrAccess(expr, member) => {
+                let obj = self.deref(&self.eval(root, expr)?, col)?;
+                self.access(&obj, member, col)
+            }
+            KexprOp::Subscript(expr, idx) => self.subscript(&self.eval(root, expr)?, *idx, col),
+            KexprOp::AddrOf(expr) => {
+                let obj = self.eval(root, expr)?;
+                match obj.loc {
+                    Loc::Mem(addr) => {
+                        Ok(obj.with(Typ::Ptr(Box::new(obj.typ.clone())), Loc::Num(addr)))
+                    }
+                    Loc::Num(_) => Err(anyhow!(
+                        "Can't take address of {} at column {col}",
+                        obj.type_name()
+                    )),
+                }
+            }
+            KexprOp::Deref(expr) => self.deref(&self.eval(root, expr)?, col),
+            KexprOp::Cast(typ, expr) => self.cast(&self.eval(root, expr)?, typ, col),
+        }
+    }
+
+    /* The value of the kexpr is the address to watch. The size is of
+     * the object which it points to, e.g. the member for "&member". */
+    pub fn expr_value(&self, root: &Value, expr: &str) -> Result<KexprValue> {
+        let kexpr = parse_kexpr(expr)?;
+        let value = self.eval(root, &kexpr)?;
+        if value.is_record() {
+            return Err(anyhow!("{expr} is {}, not an address", value.type_name()));
+        }
+
+        Ok(KexprValue {
+            addr: self.load(&value)? as usize,
+            size: value.target().and_then(|typ| value.size(&typ)),
+        })
+    }
+
+    /* Read the number of the kexpr, e.g. "mr64.pivot[1]" */
+    pub fn read_num(&self, root: &Value, expr: &str) -> Result<u64> {
+        Ok(self.expr_value(root, expr)?.addr as u64)
+    }
+}
+
+#[cfg(test)]
+mod eval_tests {
+    use super::*;
+
+    const INT: u32 = 1;
+    const PTR: u32 = 2;
+    const ARRAY: u32 = 3;
+    const STRUCT: u32 = 4;
+    const UNION: u32 = 5;
+    const BASE: u64 = 0xffff0000;
+
+    /* The objects of the types:
+     *
+     *   struct foo {                     struct bar {
+     *       int a;                           long x;
+     *       int b[4];                        struct foo *foo;
+     *       struct bar *bar;             };
+     *       union { long c; int d; };
+     *   };
+     *
+     * The struct foo is at BASE and the struct bar is at BASE + 0x40,
+     * whose member foo points back to BASE. */
+    fn kmem() -> Result<Kmem> {
+        let btf = Btf::parse(
+            &build_btf(&[
+                /* 1: int, 2: long, 3: int[4] */
+                ("int", INT, 4, vec![32], vec![]),
+                ("long", INT, 8, vec![64], vec![]),
+                ("", ARRAY, 0, vec![1, 1, 4], vec![]),
+                /* 4: struct foo, 5: the union in foo, 6: struct bar */
+                (
+                    "foo",
+                    STRUCT,
+                    40,
+                    vec![],
+                    vec![("a", 1, 0), ("b", 3, 32), ("bar", 8, 192), ("", 5, 256)],
+                ),
+                ("", UNION, 8, vec![], vec![("c", 2, 0), ("d", 1, 0)]),
+                ("bar", STRUCT, 16, vec![], vec![("x", 2, 0), ("foo", 7, 64)]),
+                /* 7: struct foo *, 8: struct bar * */
+                ("", PTR, 4, vec![], vec![]),
+                ("", PTR, 6, vec![], vec![]),
+            ]),
+            None,
+        )?;
+
+        let mut mem = vec![0u8; 0x50];
+        let mut put = |off: usize, val: &[u8]| mem[off..off + val.len()].copy_from_slice(val);
+        for (idx, val) in [1i32, 2, 3, 4, 5].iter().enumerate() {
+            put(idx * 4, &val.to_le_bytes());
+        }
+        put(24, &(BASE + 0x40).to_le_bytes());
+        put(32, &0x1234u64.to_le_bytes());
+        put(0x40, &7u64.to_le_bytes());
+        put(0x48, &BASE.to_le_bytes());
+
+        Ok(Kmem {
+            btf: Rc::new(btf),
+            kcore: open_kcore(&build_kcore(BASE, &mem))?,
+        })
+    }
+
+    #[test]
+    fn test_eval_kexpr() -> Result<()> {
+        let kmem = kmem()?;
+        let root = kmem.pointer("struct foo", BASE)?;
+        let value = |expr: &str| {
+            let value = kmem.expr_value(&root, expr).unwrap();
+            (value.addr as u64, value.size)
+        };
+
+        assert_eq!(value("&a"), (BASE, Some(4)));
+        assert_eq!(value("a"), (1, None));
+        assert_eq!(value("&b[2]"), (BASE + 12, Some(4)));
+        assert_eq!(value("*b"), (2, None));
+        assert_eq!(value("bar"), (BASE + 0x40, Some(16)));
+        assert_eq!(value("&bar->x"), (BASE + 0x40, Some(8)));
+        assert_eq!(value("&bar->foo->c"), (BASE + 32, Some(8)));
+        assert_eq!(value("&(*bar).foo"), (BASE + 0x48, Some(8)));
+        assert_eq!(value("&d"), (BASE + 32, Some(4)));
+        assert_eq!(value("bar->foo->d"), (0x1234, None));
+        assert_eq!(value("&((struct bar *)bar)->foo"), (BASE + 0x48, Some(8)));
+        assert_eq!(value("(long)bar"), (BASE + 0x40, None));
+        assert_eq!(value("&((int *)&c)[1]"), (BASE + 36, Some(4)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_kexpr_error() -> Result<()> {
+        let kmem = kmem()?;
+        let root = kmem.pointer("struct foo", BASE)?;
+        let err = |expr: &str| match kmem.expr_value(&root, expr) {
+            Ok(_) => panic!("Expect error for {expr}"),
+            Err(e) => e.to_string(),
+        };
+
+        assert_eq!(err("&e"), "struct foo has no member e at column 2");
+        assert_eq!(err("bar.x"), "struct bar * has no member x at column 4");
+        assert_eq!(err("a[1]"), "Can't index int by 1 at column 2");
+        assert_eq!(err("&&a"), "Can't take address of int * at column 1");
+        assert_eq!(err("*a"), "Can't dereference int at column 1");
+        assert_eq!(
+            err("(struct baz *)bar"),
+            "Unknown type struct baz at column 1"
+        );
+        assert_eq!(
+            err("(struct bar)bar"),
+            "Can't cast struct bar * to struct bar at column 1"
+        );
+        assert_eq!(err("b"), "b is int[4], not an address");
+
+        Ok(())
+    }
+}
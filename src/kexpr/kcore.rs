@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use anyhow::{anyhow, Result};
+
+pub const KCORE: &str = "/proc/kcore";
+const PT_LOAD: u32 = 1;
+
+/* The kernel memory from /proc/kcore, which is the ELF core file of
+ * the running kernel, as drgn reads it for the live kernel. */
+pub struct Kcore {
+    file: File,
+    /* The loadable segments as (vaddr, offset, size) */
+    segs: Vec<(u64, u64, u64)>,
+}
+
+fn read_at<const N: usize>(file: &File, off: u64) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    file.read_exact_at(&mut buf, off)?;
+    Ok(buf)
+}
+
+impl Kcore {
+    pub fn open(path: &str) -> Result<Kcore> {
+        let file = File::open(path).map_err(|e| anyhow!("Failed to open {path}: {e}"))?;
+        let ident = read_at::<6>(&file, 0)?;
+        /* Only ELF64 in little endian, which is the case of x86_64 and
+         * aarch64 */
+        if ident != [0x7f, b'E', b'L', b'F', 2, 1] {
+            return Err(anyhow!("{path} is not a 64-bit little endian ELF"));
+        }
+
+        let phoff = u64::from_le_bytes(read_at(&file, 0x20)?);
+        let phentsize = u16::from_le_bytes(read_at(&file, 0x36)?) as u64;
+        let phnum = u16::from_le_bytes(read_at(&file, 0x38)?) as u64;
+
+        let mut segs = Vec::new();
+        for idx in 0..phnum {
+            let phdr = read_at::<56>(&file, phoff + idx * phentsize)?;
+            let field = |off: usize| u64::from_le_bytes(phdr[off..off + 8].try_into().unwrap());
+            if u32::from_le_bytes(phdr[0..4].try_into().unwrap()) != PT_LOAD {
+                continue;
+            }
+            /* p_vaddr, p_offset and p_filesz */
+            segs.push((field(0x10), field(0x08), field(0x20)));
+        }
+
+        Ok(Kcore { file, segs })
+    }
+
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let len = buf.len() as u64;
+        let err = || anyhow!("Failed to read {len} bytes at {addr:#x} of kernel");
+        let &(vaddr, offset, _) = self
+            .segs
+            .iter()
+            .find(|&&(vaddr, _, size)| addr >= vaddr && addr - vaddr + len <= size)
+            .ok_or_else(err)?;
+
+        self.file
+            .read_exact_at(buf, offset + addr - vaddr)
+            .map_err(|_| err())
+    }
+
+    /* Read the number of size bytes, which is little endian like the ELF */
+    pub fn read_num(&self, addr: u64, size: u64) -> Result<u64> {
+        let mut buf = [0; 8];
+        match size {
+            1 | 2 | 4 | 8 => self.read(addr, &mut buf[..size as usize])?,
+            _ => return Err(anyhow!("Can't read {size} bytes as number")),
+        }
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+/* Build the ELF core with one segment of the memory at vaddr */
+#[cfg(test)]
+pub fn build_kcore(vaddr: u64, mem: &[u8]) -> Vec<u8> {
+    let mut data = vec![0u8; 0x100];
+    data[..6].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1]);
+    data[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+    data[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+    data[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+    data[0x40..0x44].copy_from_slice(&PT_LOAD.to_le_bytes());
+    data[0x48..0x50].copy_from_slice(&0x100u64.to_le_bytes());
+    data[0x50..0x58].copy_from_slice(&vaddr.to_le_bytes());
+    data[0x60..0x68].copy_from_slice(&(mem.len() as u64).to_le_bytes());
+    data.extend_from_slice(mem);
+    data
+}
+
+/* Open the ELF core from the data, which is removed after opened */
+#[cfg(test)]
+pub fn open_kcore(data: &[u8]) -> Result<Kcore> {
+    let path = std::env::temp_dir().join(format!("kcore-{:p}", data));
+    std::fs::write(&path, data)?;
+    let kcore = Kcore::open(path.to_str().unwrap());
+    std::fs::remove_file(&path)?;
+    kcore
+}
+
+#[cfg(test)]
+mod kcore_tests {
+    use super::*;
+
+    #[test]
+    fn test_read_kcore() -> Result<()> {
+        let mut mem = vec![0u8; 0x10];
+        mem[8..].copy_from_slice(&0x1122334455667788u64.to_le_bytes());
+        let kcore = open_kcore(&build_kcore(0xffff0000, &mem))?;
+
+        assert_eq!(kcore.read_num(0xffff0008, 8)?, 0x1122334455667788);
+        assert_eq!(kcore.read_num(0xffff0008, 2)?, 0x7788);
+        assert_eq!(kcore.read_num(0xffff000c, 4)?, 0x11223344);
+        assert!(kcore.read_num(0xffff000c, 8).is_err());
+        assert!(kcore.read_num(0xfffe0000, 1).is_err());
+        assert!(kcore.read_num(0xffff0000, 3).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,904 @@
+#[cfg(any(feature = "kexpr", test))]
+mod btf;
+#[cfg(any(feature = "kexpr", test))]
+mod eval;
+#[cfg(any(feature = "kexpr", test))]
+mod kcore;
+#[cfg(any(feature = "kexpr", test))]
+mod parser;
+#[cfg(any(feature = "kexpr", test))]
+mod tree;
+
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use libc::c_int;
+
+#[cfg(feature = "kexpr")]
+use crate::kexpr::btf::*;
+#[cfg(feature = "kexpr")]
+use crate::kexpr::eval::*;
+#[cfg(feature = "kexpr")]
+use crate::kexpr::parser::*;
+#[cfg(feature = "kexpr")]
+use crate::kexpr::tree::*;
+
+#[cfg(feature = "kexpr")]
+use drgn_knight::*;
+#[cfg(feature = "kexpr")]
+use std::fs::OpenOptions;
+#[cfg(feature = "kexpr")]
+use std::os::fd::AsRawFd;
+#[cfg(feature = "kexpr")]
+use std::os::unix::fs::OpenOptionsExt;
+
+/* The value of kexpr which is the address to watch, with the size
+ * of the object at the address if it is known. */
+pub struct KexprValue {
+    pub addr: usize,
+    pub size: Option<u64>,
+}
+
+/* Get the address of global variable */
+#[cfg(feature = "kexpr")]
+fn var_addr(prog: &Program, sym: &str) -> Result<u64> {
+    let addr = prog
+        .find_object_variable(sym)?
+        .address_of()
+        .ok_or(anyhow!("Fail to get address of {sym}"))?
+        .to_num()?;
+    Ok(addr)
+}
+
+/* Evaluate the kexpr on the object of drgn-knight, which is only for
+ * the global variable whose type is not in BTF. Without the type, only
+ * the members and their address can be taken. */
+#[cfg(feature = "kexpr")]
+fn eval_object(root: &Object, kexpr: &Kexpr) -> Result<Object> {
+    let col = kexpr.col;
+    let no_member = |member: &str| anyhow!("No member {member} at column {col}");
+
+    match &kexpr.op {
+        KexprOp::Member(member) => root.deref_member(member).ok_or_else(|| no_member(member)),
+        KexprOp::Access(expr, member) => eval_object(root, expr)?
+            .member(member)
+            .ok_or_else(|| no_member(member)),
+        KexprOp::PtrAccess(expr, member) => eval_object(root, expr)?
+            .deref_member(member)
+            .ok_or_else(|| no_member(member)),
+        KexprOp::AddrOf(expr) => eval_object(root, expr)?
+            .address_of()
+            .ok_or_else(|| anyhow!("Can't take address at column {col}")),
+        _ => Err(anyhow!(
+            "Only '.', '->' and '&' are supported at column {col} without the type in BTF"
+        )),
+    }
+}
+
+#[cfg(feature = "kexpr")]
+pub fn task_kexpr(pid: u64, expr: &str) -> Result<KexprValue> {
+    let prog = Program::new()?;
+    let kmem = Kmem::new()?;
+    let task = kmem.pointer("struct task_struct", prog.find_task(pid)?.to_num()?)?;
+    kmem.expr_value(&task, expr)
+        .map_err(|e| anyhow!("Invalid kexpr {expr}: {e}"))
+}
+
+#[cfg(feature = "kexpr")]
+pub fn global_kexpr(sym: &str, expr: &str) -> Result<KexprValue> {
+    let prog = Program::new()?;
+    let kmem = Kmem::new()?;
+    let addr = var_addr(&prog, sym)?;
+    let err = |e| anyhow!("Invalid {expr} for {sym}: {e}");
+
+    if let Some(root) = kmem.var_pointer(sym, addr) {
+        return kmem.expr_value(&root, expr).map_err(err);
+    }
+
+    /* The members are dereferenced from the pointer of root */
+    let root = prog
+        .find_object_variable(sym)?
+        .address_of()
+        .ok_or(anyhow!("Fail to get address of {sym}"))?;
+    let obj = eval_object(&root, &parse_kexpr(expr)?).map_err(err)?;
+    let addr = obj
+        .to_num()
+        .map_err(|_| anyhow!("{expr} of {sym} is not an address"))?;
+
+    Ok(KexprValue {
+        addr: addr as usize,
+        size: None,
+    })
+}
+
+/* Get the offsets of per-CPU area for the CPUs */
+#[cfg(feature = "kexpr")]
+pub fn per_cpu_offsets(cpus: &[c_int]) -> Result<Vec<usize>> {
+    let prog = Program::new()?;
+    let kmem = Kmem::new()?;
+    /* unsigned long __per_cpu_offset[NR_CPUS] */
+    let offsets = var_addr(&prog, "__per_cpu_offset")?;
+
+    cpus.iter()
+        .map(|&cpu| {
+            let offset = kmem
+                .read_u64(offsets + cpu as u64 * 8)
+                .map_err(|e| anyhow!("Fail to get per-CPU offset of CPU {cpu}: {e}"))?;
+            Ok(offset as usize)
+        })
+        .collect()
+}
+
+/* Evaluate the kexpr on the instance of per-CPU variable for each CPU */
+#[cfg(feature = "kexpr")]
+pub fn percpu_global_kexpr(sym: &str, expr: &str, cpus: &[c_int]) -> Result<Vec<KexprValue>> {
+    let prog = Program::new()?;
+    let kmem = Kmem::new()?;
+    let addr = var_addr(&prog, sym)? as usize;
+
+    let offsets = per_cpu_offsets(cpus)?;
+    let mut values = Vec::new();
+    for (cpu, offset) in cpus.iter().zip(offsets) {
+        let root = kmem
+            .var_pointer(sym, addr.wrapping_add(offset) as u64)
+            .ok_or(anyhow!(
+                "Fail to find type of per-CPU variable {sym} in BTF"
+            ))?;
+        let value = kmem
+            .expr_value(&root, expr)
+            .map_err(|e| anyhow!("Invalid {expr} for {sym} on CPU {cpu}: {e}"))?;
+        values.push(value);
+    }
+
+    Ok(values)
+}
+
+/* Get the size of the global variable from BTF, where only the per-CPU
+ * variables are unless the kernel is built with all the variables. */
+#[cfg(feature = "kexpr")]
+pub fn sym_size(sym: &str) -> Result<u64> {
+    let btf = Btf::load()?;
+    btf.find_var(sym)
+        .and_then(|typ| btf.size_of(typ))
+        .ok_or(anyhow!("Fail to find type of {sym} in BTF"))
+}
+
+#[cfg(feature = "kexpr")]
+fn bus_to_subsys(prog: &Program, bus: &str) -> Result<Object> {
+    let bus_kset = prog.find_object_variable("bus_kset")?;
+    let bus_kset_list = bus_kset
+        .deref_member("list")
+        .ok_or(anyhow!("Fail to find member list"))?;
+    let subsys_list = List::new(bus_kset_list, "struct subsys_private", "subsys.kobj.entry")?;
+
+    for subsys in subsys_list {
+        let Some(bus_type) = subsys.deref_member("bus") else {
+            continue;
+        };
+
+        let Some(bus_name) = bus_type.deref_member("name") else {
+            continue;
+        };
+
+        let Ok(name) = bus_name.to_str() else {
+            continue;
+        };
+
+        if bus == name {
+            return Ok(subsys);
+        };
+    }
+
+    Err(anyhow!(format!("Bus {bus} is not found")))
+}
+
+#[cfg(feature = "kexpr")]
+fn find_busdev(prog: &Program, bus: &str, dev_name: &str) -> Result<Object> {
+    let sp = bus_to_subsys(prog, bus)?;
+    let sp_k_list = sp
+        .deref_member("klist_devices")
+        .ok_or(anyhow!("Fail to find member klist_devices"))?
+        .member("k_list")
+        .ok_or(anyhow!("Fail to find member k_list"))?;
+
+    let dev_list = List::new(sp_k_list, "struct device_private", "knode_bus.n_node")?;
+
+    for dev in dev_list {
+        let device = dev
+            .deref_member("device")
+            .ok_or(anyhow!("Fail to find member device"))?;
+        let device_name = device
+            .deref_member("kobj")
+            .ok_or(anyhow!("Fail to find member kobj"))?
+            .member("name")
+            .ok_or(anyhow!("Fail to find member name"))?
+            .to_str()?;
+
+        if device_name == dev_name {
+            return Ok(device);
+        }
+    }
+
+    Err(anyhow!("Fail to find {dev_name} on bus {bus}"))
+}
+
+/* Get the address of struct file of the opened file descriptor of the
+ * task */
+#[cfg(feature = "kexpr")]
+fn find_task_file(prog: &Program, kmem: &Kmem, pid: u64, fd: u64) -> Result<Value> {
+    let task = prog.find_task(pid)?;
+    let fdt = task
+        .deref_member("files")
+        .ok_or(anyhow!("Fail to find member files"))?
+        .deref_member("fdt")
+        .ok_or(anyhow!("Fail to find member fdt"))?;
+
+    let max_fds = fdt
+        .deref_member("max_fds")
+        .ok_or(anyhow!("Fail to find member max_fds"))?
+        .to_num()?;
+    if fd >= max_fds {
+        return Err(anyhow!("fd {fd} of pid {pid} is not opened"));
+    }
+
+    /* struct file **fd */
+    let fds = fdt
+        .deref_member("fd")
+        .ok_or(anyhow!("Fail to find member fd"))?
+        .to_num()?;
+    let file = kmem
+        .read_u64(fds + fd * 8)
+        .map_err(|e| anyhow!("Fail to get fd {fd} of pid {pid}: {e}"))?;
+    if file == 0 {
+        return Err(anyhow!("fd {fd} of pid {pid} is not opened"));
+    }
+
+    kmem.pointer("struct file", file)
+}
+
+#[cfg(feature = "kexpr")]
+pub fn file_kexpr(pid: u64, fd: u64, expr: &str) -> Result<KexprValue> {
+    let prog = Program::new()?;
+    let kmem = Kmem::new()?;
+    let file = find_task_file(&prog, &kmem, pid, fd)?;
+    kmem.expr_value(&file, expr)
+        .map_err(|e| anyhow!("Invalid {expr} for fd {fd} of pid {pid}: {e}"))
+}
+
+/* Get the struct socket from private_data of the file, which must be
+ * a socket file. */
+#[cfg(feature = "kexpr")]
+pub fn sock_kexpr(pid: u64, fd: u64, expr: &str) -> Result<KexprValue> {
+    let prog = Program::new()?;
+    let kmem = Kmem::new()?;
+    let file = find_task_file(&prog, &kmem, pid, fd)?;
+
+    let f_op = kmem.read_num(&file, "f_op")?;
+    let socket_file_ops = var_addr(&prog, "socket_file_ops")?;
+    if f_op != socket_file_ops {
+        return Err(anyhow!("fd {fd} of pid {pid} is not a socket"));
+    }
+
+    let sock = kmem.pointer("struct socket", kmem.read_num(&file, "private_data")?)?;
+    kmem.expr_value(&sock, expr)
+        .map_err(|e| anyhow!("Invalid {expr} for socket fd {fd} of pid {pid}: {e}"))
+}
+
+/* Get the struct inode of the path. The path is opened by ourselves,
+ * so the inode can be found from the opened file. */
+#[cfg(feature = "kexpr")]
+fn find_path_inode(prog: &Program, kmem: &Kmem, path: &str) -> Result<Value> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH)
+        .open(path)
+        .map_err(|e| anyhow!("Fail to open {path}: {e}"))?;
+
+    let pid = std::process::id() as u64;
+    let fd = file.as_raw_fd() as u64;
+    let file = find_task_file(prog, kmem, pid, fd)?;
+    kmem.pointer("struct inode", kmem.read_num(&file, "f_inode")?)
+}
+
+#[cfg(feature = "kexpr")]
+pub fn inode_kexpr(path: &str, expr: &str) -> Result<KexprValue> {
+    let prog = Program::new()?;
+    let kmem = Kmem::new()?;
+    let inode = find_path_inode(&prog, &kmem, path)?;
+    kmem.expr_value(&inode, expr)
+        .map_err(|e| anyhow!("Invalid {expr} for inode of {path}: {e}"))
+}
+
+#[cfg(feature = "kexpr")]
+pub fn sb_kexpr(path: &str, expr: &str) -> Result<KexprValue> {
+    let prog = Program::new()?;
+    let kmem = Kmem::new()?;
+    let inode = find_path_inode(&prog, &kmem, path)?;
+    let sb = kmem.pointer("struct super_block", kmem.read_num(&inode, "i_sb")?)?;
+    kmem.expr_value(&sb, expr)
+        .map_err(|e| anyhow!("Invalid {expr} for superblock of {path}: {e}"))
+}
+
+#[cfg(feature = "kexpr")]
+fn find_module(prog: &Program, name: &str) -> Result<Object> {
+    let modules = prog.find_object_variable("modules")?;
+    let mod_list = List::new(modules, "struct module", "list")?;
+
+    for module in mod_list {
+        let Some(mod_name) = module.deref_member("name") else {
+            continue;
+        };
+
+        let Ok(mod_name) = mod_name.to_str() else {
+            continue;
+        };
+
+        if mod_name == name {
+            return Ok(module);
+        }
+    }
+
+    Err(anyhow!("Module {name} is not loaded"))
+}
+
+#[cfg(feature = "kexpr")]
+pub fn module_kexpr(name: &str, expr: &str) -> Result<KexprValue> {
+    let prog = Program::new()?;
+    let kmem = Kmem::new()?;
+    let module = kmem.pointer("struct module", find_module(&prog, name)?.to_num()?)?;
+    kmem.expr_value(&module, expr)
+        .map_err(|e| anyhow!("Invalid {expr} for module {name}: {e}"))
+}
+
+/* Look up the entry of index in the xarray, which is the radix tree
+ * of the kernel since v4.20. */
+#[cfg(feature = "kexpr")]
+fn xa_load(kmem: &Kmem, xa: u64, index: u64) -> Result<u64> {
+    let xa = kmem.pointer("struct xarray", xa)?;
+    let mut entry = kmem.read_num(&xa, "xa_head")?;
+    if !is_node_entry(entry) {
+        return Ok(if index == 0 { entry } else { 0 });
+    }
+
+    let mut top = true;
+    while is_node_entry(entry) {
+        let node = kmem.pointer("struct xa_node", entry - 2)?;
+        let shift = kmem.read_num(&node, "shift")?;
+        let nr_slots = kmem
+            .expr_value(&node, "&slots")?
+            .size
+            .ok_or(anyhow!("Fail to get size of slots"))?
+            / 8;
+
+        /* The index is out of the range of the whole tree */
+        if top && (index >> shift) >= nr_slots {
+            return Ok(0);
+        }
+        top = false;
+
+        let slot = (index >> shift) & (nr_slots - 1);
+        entry = kmem.read_num(&node, &format!("slots[{slot}]"))?;
+    }
+
+    Ok(entry)
+}
+
+/* Look up the entry of index in the maple tree, like mtree_load() in
+ * the kernel. */
+#[cfg(feature = "kexpr")]
+fn mtree_load(kmem: &Kmem, mt: u64, index: u64) -> Result<u64> {
+    let read = |addr: u64, typ: u64, member: &str, idx: u64| {
+        let node = kmem.pointer("struct maple_node", addr)?;
+        let expr = match typ {
+            MAPLE_DENSE => format!("{member}[{idx}]"),
+            MAPLE_ARANGE_64 => format!("ma64.{member}[{idx}]"),
+            _ => format!("mr64.{member}[{idx}]"),
+        };
+        kmem.read_num(&node, &expr)
+    };
+
+    let mt = kmem.pointer("struct maple_tree", mt)?;
+    maple_walk(kmem.read_num(&mt, "ma_root")?, index, &read)
+}
+
+/* Get the struct irq_desc of the IRQ number, which is like
+ * irq_to_desc() in the kernel. The descriptors are in the maple tree
+ * since v6.5 or in the radix tree before if CONFIG_SPARSE_IRQ is set,
+ * otherwise they are in the static array. */
+#[cfg(feature = "kexpr")]
+fn find_irq_desc(prog: &Program, kmem: &Kmem, irq: u64) -> Result<Value> {
+    let nr_irqs = prog.find_object_variable("nr_irqs")?.to_num()?;
+    if irq >= nr_irqs {
+        return Err(anyhow!("IRQ {irq} is out of nr_irqs {nr_irqs}"));
+    }
+
+    let addr = if let Ok(mt) = var_addr(prog, "sparse_irqs") {
+        mtree_load(kmem, mt, irq)?
+    } else if let Ok(xa) = var_addr(prog, "irq_desc_tree") {
+        xa_load(kmem, xa, irq)?
+    } else {
+        var_addr(prog, "irq_desc")? + irq * kmem.size_of("struct irq_desc")?
+    };
+
+    if addr == 0 {
+        return Err(anyhow!("IRQ {irq} is not allocated"));
+    }
+
+    kmem.pointer("struct irq_desc", addr)
+}
+
+#[cfg(feature = "kexpr")]
+pub fn irq_kexpr(irq: u64, expr: &str) -> Result<KexprValue> {
+    let prog = Program::new()?;
+    let kmem = Kmem::new()?;
+    let desc = find_irq_desc(&prog, &kmem, irq)?;
+    kmem.expr_value(&desc, expr)
+        .map_err(|e| anyhow!("Invalid {expr} for IRQ {irq}: {e}"))
+}
+
+/* The alignment of the private area after struct net_device, which
+ * is NETDEV_ALIGN in the kernel. */
+#[cfg(feature = "kexpr")]
+const NETDEV_ALIGN: u64 = 32;
+
+/* Get the pointer of network namespace of the task, or init_net if
+ * the pid is not specified. */
+#[cfg(feature = "kexpr")]
+fn find_netns(prog: &Program, netns_pid: Option<u64>) -> Result<Object> {
+    let Some(pid) = netns_pid else {
+        return prog
+            .find_object_variable("init_net")?
+            .address_of()
+            .ok_or(anyhow!("Fail to get address of init_net"));
+    };
+
+    let task = prog.find_task(pid)?;
+    task.deref_member("nsproxy")
+        .ok_or(anyhow!("Fail to find member nsproxy"))?
+        .deref_member("net_ns")
+        .ok_or(anyhow!("Fail to find member net_ns"))
+}
+
+#[cfg(feature = "kexpr")]
+fn find_netdev(prog: &Program, name: &str, netns_pid: Option<u64>) -> Result<Object> {
+    let net = find_netns(prog, netns_pid)?;
+    let dev_base_head = net
+        .deref_member("dev_base_head")
+        .ok_or(anyhow!("Fail to find member dev_base_head"))?;
+    let dev_list = List::new(dev_base_head, "struct net_device", "dev_list")?;
+
+    for dev in dev_list {
+        let Some(dev_name) = dev.deref_member("name") else {
+            continue;
+        };
+
+        let Ok(dev_name) = dev_name.to_str() else {
+            continue;
+        };
+
+        if dev_name == name {
+            return Ok(dev);
+        }
+    }
+
+    match netns_pid {
+        Some(pid) => Err(anyhow!(
+            "Fail to find {name} in network namespace of pid {pid}"
+        )),
+        None => Err(anyhow!("Fail to find {name} in init_net")),
+    }
+}
+
+#[cfg(feature = "kexpr")]
+pub fn netdev_kexpr(
+    name: &str,
+    netns_pid: Option<u64>,
+    priv_type: Option<&str>,
+    expr: &str,
+) -> Result<KexprValue> {
+    let prog = Program::new()?;
+    let kmem = Kmem::new()?;
+    let dev = find_netdev(&prog, name, netns_pid)?.to_num()?;
+
+    /* The private area is after struct net_device, which is the same
+     * as netdev_priv(). */
+    let root = match priv_type {
+        Some(typ) => {
+            let size = kmem.size_of("struct net_device")?;
+            let offset = (size + NETDEV_ALIGN - 1) & !(NETDEV_ALIGN - 1);
+            kmem.pointer(typ, dev + offset)?
+        }
+        None => kmem.pointer("struct net_device", dev)?,
+    };
+    kmem.expr_value(&root, expr)
+        .map_err(|e| anyhow!("Invalid {expr} for network device {name}: {e}"))
+}
+
+#[cfg(feature = "kexpr")]
+macro_rules! define_dev_kexpr {
+    ($fname: tt, $bus: literal, $struct: literal) => {
+        pub fn $fname(dev_name: &str, expr: &str) -> Result<KexprValue> {
+            let prog = Program::new()?;
+            let kmem = Kmem::new()?;
+            let busdev = find_busdev(&prog, $bus, dev_name)?;
+            let dev = busdev
+                .container_of($struct, "dev")
+                .ok_or(anyhow!("Fail to get data for device {dev_name}"))?;
+            let dev = kmem.pointer($struct, dev.to_num()?)?;
+            kmem.expr_value(&dev, expr)
+                .map_err(|e| anyhow!("Invalid {expr} for device {dev_name}: {e}"))
+        }
+    };
+}
+
+#[cfg(not(feature = "kexpr"))]
+macro_rules! define_dev_kexpr {
+    ($fname: tt, $bus: literal, $struct: literal) => {
+        pub fn $fname(_dev_name: &str, _expr: &str) -> Result<KexprValue> {
+            Err(anyhow!("kexpr is not configured"))
+        }
+    };
+}
+
+define_dev_kexpr!(pcidev_kexpr, "pci", "struct pci_dev");
+define_dev_kexpr!(usbdev_kexpr, "usb", "struct usb_device");
+define_dev_kexpr!(platdev_kexpr, "platform", "struct platform_device");
+
+#[cfg(not(feature = "kexpr"))]
+pub fn task_kexpr(_pid: u64, _expr: &str) -> Result<KexprValue> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+#[cfg(not(feature = "kexpr"))]
+pub fn global_kexpr(_sym: &str, _expr: &str) -> Result<KexprValue> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+#[cfg(not(feature = "kexpr"))]
+pub fn file_kexpr(_pid: u64, _fd: u64, _expr: &str) -> Result<KexprValue> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+#[cfg(not(feature = "kexpr"))]
+pub fn sock_kexpr(_pid: u64, _fd: u64, _expr: &str) -> Result<KexprValue> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+#[cfg(not(feature = "kexpr"))]
+pub fn inode_kexpr(_path: &str, _expr: &str) -> Result<KexprValue> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+#[cfg(not(feature = "kexpr"))]
+pub fn sb_kexpr(_path: &str, _expr: &str) -> Result<KexprValue> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+#[cfg(not(feature = "kexpr"))]
+pub fn module_kexpr(_name: &str, _expr: &str) -> Result<KexprValue> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+#[cfg(not(feature = "kexpr"))]
+pub fn irq_kexpr(_irq: u64, _expr: &str) -> Result<KexprValue> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+#[cfg(not(feature = "kexpr"))]
+pub fn netdev_kexpr(
+    _name: &str,
+    _netns_pid: Option<u64>,
+    _priv_type: Option<&str>,
+    _expr: &str,
+) -> Result<KexprValue> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+#[cfg(not(feature = "kexpr"))]
+pub fn per_cpu_offsets(_cpus: &[c_int]) -> Result<Vec<usize>> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+#[cfg(not(feature = "kexpr"))]
+pub fn percpu_global_kexpr(_sym: &str, _expr: &str, _cpus: &[c_int]) -> Result<Vec<KexprValue>> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+#[cfg(not(feature = "kexpr"))]
+pub fn sym_size(_sym: &str) -> Result<u64> {
+    Err(anyhow!("kexpr is not configured"))
+}
+
+/* Parse the file descriptor of the process in the form "pid:fd" */
+pub fn parse_pid_fd(s: &str) -> Result<(u64, u64)> {
+    let err = || anyhow!("Invalid {s}, expect pid:fd");
+    let (pid, fd) = s.split_once(':').ok_or_else(err)?;
+    let pid = pid.trim().parse::<u64>().map_err(|_| err())?;
+    let fd = fd.trim().parse::<u64>().map_err(|_| err())?;
+    Ok((pid, fd))
+}
+
+/* The kernel object where the kexpr is dereferenced from */
+#[derive(Clone, Debug)]
+pub enum KexprRoot {
+    Task(u64),
+    PciDev(String),
+    UsbDev(String),
+    PlatDev(String),
+    Global(String),
+    File(u64, u64),
+    Sock(u64, u64),
+    Inode(String),
+    Sb(String),
+    Module(String),
+    Irq(u64),
+    NetDev {
+        name: String,
+        netns_pid: Option<u64>,
+        priv_type: Option<String>,
+    },
+}
+
+impl fmt::Display for KexprRoot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KexprRoot::Task(pid) => write!(f, "pid-task {pid}"),
+            KexprRoot::PciDev(dev) => write!(f, "pci-dev {dev}"),
+            KexprRoot::UsbDev(dev) => write!(f, "usb-dev {dev}"),
+            KexprRoot::PlatDev(dev) => write!(f, "plat-dev {dev}"),
+            KexprRoot::Global(sym) => write!(f, "global {sym}"),
+            KexprRoot::File(pid, fd) => write!(f, "file {pid}:{fd}"),
+            KexprRoot::Sock(pid, fd) => write!(f, "sock {pid}:{fd}"),
+            KexprRoot::Inode(path) => write!(f, "inode {path}"),
+            KexprRoot::Sb(path) => write!(f, "sb {path}"),
+            KexprRoot::Module(name) => write!(f, "module {name}"),
+            KexprRoot::Irq(irq) => write!(f, "irq {irq}"),
+            KexprRoot::NetDev {
+                name,
+                netns_pid,
+                priv_type,
+            } => {
+                write!(f, "net-dev {name}")?;
+                if let Some(pid) = netns_pid {
+                    write!(f, " of netns-pid {pid}")?;
+                }
+                if let Some(typ) = priv_type {
+                    write!(f, " as ({typ} *)netdev_priv")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn eval_kexpr(root: &KexprRoot, expr: &str) -> Result<KexprValue> {
+    match root {
+        KexprRoot::Task(pid) => task_kexpr(*pid, expr),
+        KexprRoot::PciDev(dev) => pcidev_kexpr(dev, expr),
+        KexprRoot::UsbDev(dev) => usbdev_kexpr(dev, expr),
+        KexprRoot::PlatDev(dev) => platdev_kexpr(dev, expr),
+        KexprRoot::Global(sym) => global_kexpr(sym, expr),
+        KexprRoot::File(pid, fd) => file_kexpr(*pid, *fd, expr),
+        KexprRoot::Sock(pid, fd) => sock_kexpr(*pid, *fd, expr),
+        KexprRoot::Inode(path) => inode_kexpr(path, expr),
+        KexprRoot::Sb(path) => sb_kexpr(path, expr),
+        KexprRoot::Module(name) => module_kexpr(name, expr),
+        KexprRoot::Irq(irq) => irq_kexpr(*irq, expr),
+        KexprRoot::NetDev {
+            name,
+            netns_pid,
+            priv_type,
+        } => netdev_kexpr(name, *netns_pid, priv_type.as_deref(), expr),
+    }
+}
+
+#[cfg(feature = "kexpr")]
+#[cfg(test)]
+mod kexpr_tests {
+    use super::*;
+    use crate::hexstr2int;
+    use crate::utils::get_possible_cpus;
+    use anyhow::Result;
+    use std::fs;
+    use std::fs::File;
+    use std::net::UdpSocket;
+    use std::os::fd::AsRawFd;
+    use std::process::Command;
+
+    macro_rules! exec {
+        ($args:expr) => {
+            hexstr2int(
+                &String::from_utf8(
+                    Command::new("./tests/kexpr.py")
+                        .args($args)
+                        .output()
+                        .expect("Fail to execute kexpr")
+                        .stdout,
+                )
+                .expect("Invalid output from kexpr.py")
+                .trim()
+                .to_string(),
+            )
+            .expect("Fail to convert kexpr output to usize")
+        };
+    }
+
+    #[test]
+    fn test_task_struct_kexpr() -> Result<()> {
+        let expect = exec!(["--pid", "1", "&on_rq"]);
+        assert_eq!(expect, task_kexpr(1, "&on_rq")?.addr);
+        let expect = exec!(["--pid", "1", "parent"]);
+        assert_eq!(expect, task_kexpr(1, "parent")?.addr);
+
+        let expect = exec!(["--pid", "1", "&cpus_mask.bits[0]"]);
+        assert_eq!(expect, task_kexpr(1, "&cpus_mask.bits[0]")?.addr);
+        let expect = exec!(["--pid", "1", "&mm->mmap_base"]);
+        assert_eq!(expect, task_kexpr(1, "&(*mm).mmap_base")?.addr);
+
+        let expect = exec!(["--pid", "1", "--size", "&on_rq"]);
+        assert_eq!(Some(expect as u64), task_kexpr(1, "&on_rq")?.size);
+        let expect = exec!(["--pid", "1", "--size", "parent"]);
+        assert_eq!(Some(expect as u64), task_kexpr(1, "parent")?.size);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_global_kexpr() -> Result<()> {
+        let expect = exec!(["--global", "init_task", "mm"]);
+        assert_eq!(expect, global_kexpr("init_task", "mm")?.addr);
+        let expect = exec!(["--global", "init_net", "&ipv4.sysctl_ip_default_ttl"]);
+        assert_eq!(
+            expect,
+            global_kexpr("init_net", "&ipv4.sysctl_ip_default_ttl")?.addr
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_percpu_global_kexpr() -> Result<()> {
+        let cpus = get_possible_cpus()?;
+        let values = percpu_global_kexpr("runqueues", "&nr_running", &cpus)?;
+        let offsets = per_cpu_offsets(&cpus)?;
+        let base = global_kexpr("runqueues", "&nr_running")?.addr;
+
+        for ((cpu, value), offset) in cpus.iter().zip(values).zip(offsets) {
+            let expect = exec!([
+                "--global",
+                "runqueues",
+                "--cpu",
+                &cpu.to_string(),
+                "&nr_running"
+            ]);
+            assert_eq!(expect, value.addr);
+            assert_eq!(expect, base.wrapping_add(offset));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_kexpr() -> Result<()> {
+        let pid = std::process::id() as u64;
+        let file = File::open("/proc/self/status")?;
+        let fd = file.as_raw_fd() as u64;
+        let task_fd = format!("{pid}:{fd}");
+
+        let expect = exec!(["--file", &task_fd, "&f_pos"]);
+        assert_eq!(expect, file_kexpr(pid, fd, "&f_pos")?.addr);
+
+        let sock = UdpSocket::bind("127.0.0.1:0")?;
+        let fd = sock.as_raw_fd() as u64;
+        let task_fd = format!("{pid}:{fd}");
+
+        let expect = exec!(["--sock", &task_fd, "&state"]);
+        assert_eq!(expect, sock_kexpr(pid, fd, "&state")?.addr);
+        let expect = exec!(["--sock", &task_fd, "&sk.sk_rcvbuf"]);
+        assert_eq!(expect, sock_kexpr(pid, fd, "&sk->sk_rcvbuf")?.addr);
+        assert!(sock_kexpr(pid, file.as_raw_fd() as u64, "&state").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inode_kexpr() -> Result<()> {
+        for path in ["/", "/proc/version", "./tests/kexpr.py"] {
+            let expect = exec!(["--inode", path, "&i_size"]);
+            assert_eq!(expect, inode_kexpr(path, "&i_size")?.addr);
+            let expect = exec!(["--sb", path, "&s_flags"]);
+            assert_eq!(expect, sb_kexpr(path, "&s_flags")?.addr);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_module_kexpr() -> Result<()> {
+        let modules = fs::read_dir("/sys/module/").unwrap();
+        for module in modules {
+            let module = module.unwrap();
+            /* The built-in modules don't have struct module */
+            if !module.path().join("initstate").exists() {
+                continue;
+            }
+
+            let name = module.file_name();
+            let name = name.to_str().unwrap();
+            let expect = exec!(["--module", name, "&refcnt"]);
+            assert_eq!(expect, module_kexpr(name, "&refcnt")?.addr);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_irq_kexpr() -> Result<()> {
+        let irqs = fs::read_dir("/proc/irq/").unwrap();
+        for irq in irqs {
+            let irq = irq.unwrap().file_name();
+            let Ok(irq) = irq.to_str().unwrap().parse::<u64>() else {
+                continue;
+            };
+
+            let expect = exec!(["--irq", &irq.to_string(), "&irq_count"]);
+            assert_eq!(expect, irq_kexpr(irq, "&irq_count")?.addr);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_netdev_kexpr() -> Result<()> {
+        let devices = fs::read_dir("/sys/class/net/").unwrap();
+        for dev in devices {
+            let dev_name = dev.unwrap().file_name();
+            let dev = dev_name.to_str().unwrap();
+            let expect = exec!(["--net_dev", dev, "&mtu"]);
+            assert_eq!(expect, netdev_kexpr(dev, None, None, "&mtu")?.addr);
+            let expect = exec!(["--net_dev", dev, "--netns_pid", "1", "&mtu"]);
+            assert_eq!(expect, netdev_kexpr(dev, Some(1), None, "&mtu")?.addr);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pcidev_kexpr() -> Result<()> {
+        let devices = fs::read_dir("/sys/bus/pci/devices/").unwrap();
+        for dev in devices {
+            let dev_name = dev.unwrap().file_name();
+            let dev = dev_name.to_str().unwrap();
+            let expect = exec!(["--pci_dev", dev, "&subsystem_vendor"]);
+            assert_eq!(expect, pcidev_kexpr(&dev, "&subsystem_vendor")?.addr);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_usbdev_kexpr() -> Result<()> {
+        let devices = fs::read_dir("/sys/bus/usb/devices/").unwrap();
+        for dev in devices {
+            let dev_name = dev.unwrap().file_name();
+            let dev = dev_name.to_str().unwrap();
+            let expect = exec!(["--usb_dev", dev, "&devaddr"]);
+            assert_eq!(expect, usbdev_kexpr(&dev, "&devaddr")?.addr);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_platdev_kexpr() -> Result<()> {
+        let devices = fs::read_dir("/sys/bus/platform/devices/").unwrap();
+        for dev in devices {
+            let dev_name = dev.unwrap().file_name();
+            let dev = dev_name.to_str().unwrap();
+            let expect = exec!(["--plat_dev", dev, "&id"]);
+            assert_eq!(expect, platdev_kexpr(&dev, "&id")?.addr);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+
+/* The parser of kexpr, which is the C expression based on the member
+ * of a root object. The grammar:
+ *
+ *   expr    := unary
+ *   unary   := '&' unary | '*' unary | '(' TYPE ')' unary | postfix
+ *   postfix := primary ('.' IDENT | '->' IDENT | '[' NUMBER ']')*
+ *   primary := IDENT | '(' expr ')'
+ *   TYPE    := IDENT+ '*'*
+ *
+ * The IDENT in primary is the member of root object. A NUMBER is hex
+ * with "0x" prefix or decimal. */
+#[derive(PartialEq)]
+enum Token {
+    Ident(String),
+    Num(u64),
+    Dot,
+    Arrow,
+    Amp,
+    Star,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Ident(ident) => write!(f, "'{ident}'"),
+            Token::Num(num) => write!(f, "{num}"),
+            Token::Dot => write!(f, "'.'"),
+            Token::Arrow => write!(f, "'->'"),
+            Token::Amp => write!(f, "'&'"),
+            Token::Star => write!(f, "'*'"),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::LBracket => write!(f, "'['"),
+            Token::RBracket => write!(f, "']'"),
+        }
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn tokenize(expr: &str) -> Result<Vec<(usize, Token)>> {
+    let chars = expr.char_indices().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        let (pos, c) = chars[idx];
+        let col = pos + 1;
+        idx += 1;
+
+        let token = match c {
+            ' ' | '\t' => continue,
+            '.' => Token::Dot,
+            '&' => Token::Amp,
+            '*' => Token::Star,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            '-' if chars.get(idx).map(|&(_, c)| c) == Some('>') => {
+                idx += 1;
+                Token::Arrow
+            }
+            _ if is_ident_char(c) => {
+                while idx < chars.len() && is_ident_char(chars[idx].1) {
+                    idx += 1;
+                }
+                let end = chars.get(idx).map_or(expr.len(), |&(pos, _)| pos);
+                let word = &expr[pos..end];
+
+                if let Some(hex) = word.strip_prefix("0x") {
+                    let num = u64::from_str_radix(hex, 16)
+                        .map_err(|e| anyhow!("Invalid number {word} at column {col}: {e}"))?;
+                    Token::Num(num)
+                } else if c.is_ascii_digit() {
+                    let num = word
+                        .parse::<u64>()
+                        .map_err(|e| anyhow!("Invalid number {word} at column {col}: {e}"))?;
+                    Token::Num(num)
+                } else {
+                    Token::Ident(word.to_string())
+                }
+            }
+            _ => return Err(anyhow!("Unexpected '{c}' at column {col}")),
+        };
+
+        tokens.push((col, token));
+    }
+
+    Ok(tokens)
+}
+
+/* The node of kexpr, where col is the column of the operator in the
+ * expression for the error message. */
+#[derive(Debug)]
+pub struct Kexpr {
+    pub col: usize,
+    pub op: KexprOp,
+}
+
+#[derive(Debug)]
+pub enum KexprOp {
+    /* The member of root object */
+    Member(String),
+    /* expr.member */
+    Access(Box<Kexpr>, String),
+    /* expr->member */
+    PtrAccess(Box<Kexpr>, String),
+    /* expr[index] */
+    Subscript(Box<Kexpr>, u64),
+    /* &expr */
+    AddrOf(Box<Kexpr>),
+    /* *expr */
+    Deref(Box<Kexpr>),
+    /* (type)expr */
+    Cast(String, Box<Kexpr>),
+}
+
+impl fmt::Display for Kexpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.op {
+            KexprOp::Member(member) => write!(f, "{member}"),
+            KexprOp::Access(expr, member) => write!(f, "{expr}.{member}"),
+            KexprOp::PtrAccess(expr, member) => write!(f, "{expr}->{member}"),
+            KexprOp::Subscript(expr, idx) => write!(f, "{expr}[{idx}]"),
+            KexprOp::AddrOf(expr) => write!(f, "&({expr})"),
+            KexprOp::Deref(expr) => write!(f, "*({expr})"),
+            KexprOp::Cast(typ, expr) => write!(f, "(({typ}){expr})"),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end_col: usize,
+}
+
+impl Parser {
+    fn peek_at(&self, pos: usize) -> Option<&Token> {
+        self.tokens.get(pos).map(|(_, token)| token)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.peek_at(self.pos)
+    }
+
+    fn col(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.end_col, |&(col, _)| col)
+    }
+
+    fn expect(&mut self, expect: Token) -> Result<()> {
+        if self.peek() != Some(&expect) {
+            return Err(anyhow!("Expect {expect} at column {}", self.col()));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        let col = self.col();
+        match self.peek() {
+            Some(Token::Ident(ident)) => {
+                let ident = ident.clone();
+                self.pos += 1;
+                Ok(ident)
+            }
+            Some(token) => Err(anyhow!("Expect member name but {token} at column {col}")),
+            None => Err(anyhow!("Expect member name at column {col}")),
+        }
+    }
+
+    /* Check whether the type name is in the parentheses from the
+     * position. Since there is no binary operator in kexpr, a name in
+     * the parentheses is a type only if it is followed by an operand,
+     * e.g. "(pid_t)x" is a cast but "(x).y" is not. Return the name of
+     * type and the position after ')'. */
+    fn type_name(&self) -> Option<(String, usize)> {
+        let mut pos = self.pos + 1;
+        let mut words = Vec::new();
+        while let Some(Token::Ident(word)) = self.peek_at(pos) {
+            words.push(word.as_str());
+            pos += 1;
+        }
+        let mut stars = 0;
+        while let Some(Token::Star) = self.peek_at(pos) {
+            stars += 1;
+            pos += 1;
+        }
+
+        if words.is_empty() || self.peek_at(pos) != Some(&Token::RParen) {
+            return None;
+        }
+        pos += 1;
+
+        let is_operand = matches!(
+            self.peek_at(pos),
+            Some(Token::Ident(_) | Token::LParen | Token::Amp | Token::Star)
+        );
+        if !is_operand {
+            return None;
+        }
+
+        let mut typ = words.join(" ");
+        if stars > 0 {
+            typ.push(' ');
+            typ.push_str(&"*".repeat(stars));
+        }
+
+        Some((typ, pos))
+    }
+
+    fn unary(&mut self) -> Result<Kexpr> {
+        let col = self.col();
+        let op = match self.peek() {
+            Some(Token::Amp) => {
+                self.pos += 1;
+                KexprOp::AddrOf(Box::new(self.unary()?))
+            }
+            Some(Token::Star) => {
+                self.pos += 1;
+                KexprOp::Deref(Box::new(self.unary()?))
+            }
+            Some(Token::LParen) => match self.type_name() {
+                Some((typ, pos)) => {
+                    self.pos = pos;
+                    KexprOp::Cast(typ, Box::new(self.unary()?))
+                }
+                None => return self.postfix(),
+            },
+            _ => return self.postfix(),
+        };
+
+        Ok(Kexpr { col, op })
+    }
+
+    fn postfix(&mut self) -> Result<Kexpr> {
+        let mut expr = self.primary()?;
+
+        loop {
+            let col = self.col();
+            let op = match self.peek() {
+                Some(Token::Dot) => {
+                    self.pos += 1;
+                    KexprOp::Access(Box::new(expr), self.ident()?)
+                }
+                Some(Token::Arrow) => {
+                    self.pos += 1;
+                    KexprOp::PtrAccess(Box::new(expr), self.ident()?)
+                }
+                Some(Token::LBracket) => {
+                    self.pos += 1;
+                    let idx = match self.peek() {
+                        Some(&Token::Num(idx)) => idx,
+                        _ => return Err(anyhow!("Expect index at column {}", self.col())),
+                    };
+                    self.pos += 1;
+                    self.expect(Token::RBracket)?;
+                    KexprOp::Subscript(Box::new(expr), idx)
+                }
+                _ => return Ok(expr),
+            };
+
+            expr = Kexpr { col, op };
+        }
+    }
+
+    fn primary(&mut self) -> Result<Kexpr> {
+        let col = self.col();
+        match self.peek() {
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let expr = self.unary()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::Ident(_)) => Ok(Kexpr {
+                col,
+                op: KexprOp::Member(self.ident()?),
+            }),
+            Some(token) => Err(anyhow!("Unexpected {token} at column {col}")),
+            None => Err(anyhow!("Unexpected end of kexpr at column {col}")),
+        }
+    }
+}
+
+pub fn parse_kexpr(expr: &str) -> Result<Kexpr> {
+    let mut parser = Parser {
+        tokens: tokenize(expr)?,
+        pos: 0,
+        end_col: expr.len() + 1,
+    };
+
+    let kexpr = parser.unary()?;
+    if let Some(token) = parser.peek() {
+        return Err(anyhow!("Unexpected {token} at column {}", parser.col()));
+    }
+
+    Ok(kexpr)
+}
+
+#[cfg(test)]
+mod parser_tests {
+    use super::*;
+
+    fn parse(expr: &str) -> String {
+        parse_kexpr(expr).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_parse_kexpr() {
+        assert_eq!(parse("&on_rq"), "&(on_rq)");
+        assert_eq!(parse("mm->mmap_base"), "mm->mmap_base");
+        assert_eq!(parse("&cpus_mask.bits[1]"), "&(cpus_mask.bits[1])");
+        assert_eq!(parse("&se.cfs_rq->curr"), "&(se.cfs_rq->curr)");
+        assert_eq!(parse("*(parent)"), "*(parent)");
+        assert_eq!(
+            parse("&((struct foo *)ptr)->x"),
+            "&(((struct foo *)ptr)->x)"
+        );
+        assert_eq!(parse("(unsigned long **)ptr"), "((unsigned long **)ptr)");
+        assert_eq!(parse("&(pid_t)pid"), "&(((pid_t)pid))");
+        assert_eq!(parse("(parent)->pid"), "parent->pid");
+        assert_eq!(parse("arr[0x10]"), "arr[16]");
+    }
+
+    #[test]
+    fn test_parse_kexpr_error() {
+        let err = |expr: &str| parse_kexpr(expr).unwrap_err().to_string();
+
+        assert_eq!(err("mm->"), "Expect member name at column 5");
+        assert_eq!(err("bits[x]"), "Expect index at column 6");
+        assert_eq!(err("bits[1"), "Expect ']' at column 7");
+        assert_eq!(err("(parent"), "Expect ')' at column 8");
+        assert_eq!(err("&on_rq on_cpu"), "Unexpected 'on_cpu' at column 8");
+        assert_eq!(err("on_rq - 1"), "Unexpected '-' at column 7");
+        assert_eq!(err("."), "Unexpected '.' at column 1");
+    }
+}
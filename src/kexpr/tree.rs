@@ -0,0 +1,112 @@
+use anyhow::{anyhow, Result};
+
+/* enum maple_type */
+pub const MAPLE_DENSE: u64 = 0;
+pub const MAPLE_LEAF_64: u64 = 1;
+pub const MAPLE_RANGE_64: u64 = 2;
+pub const MAPLE_ARANGE_64: u64 = 3;
+
+/* The entry in xarray and maple tree which points to the internal
+ * node, like xa_is_node() in the kernel. For the maple tree, only the
+ * root entry is marked like this. */
+pub fn is_node_entry(entry: u64) -> bool {
+    entry & 3 == 2 && entry > 4096
+}
+
+/* Walk the maple tree from the root entry to the entry of index, like
+ * mtree_lookup_walk() in the kernel. The read(node, type, member, idx)
+ * gets member[idx] of the maple node, where member is "pivot" or
+ * "slot". */
+pub fn maple_walk(
+    root: u64,
+    index: u64,
+    read: &dyn Fn(u64, u64, &str, u64) -> Result<u64>,
+) -> Result<u64> {
+    if !is_node_entry(root) {
+        return Ok(if index == 0 { root } else { 0 });
+    }
+
+    let mut entry = root;
+    let mut min = 0;
+    loop {
+        let node = entry & !0xff;
+        let typ = (entry >> 3) & 0xf;
+        let nr_pivots = match typ {
+            MAPLE_DENSE => return read(node, typ, "slot", index - min),
+            MAPLE_LEAF_64 | MAPLE_RANGE_64 => 15,
+            MAPLE_ARANGE_64 => 9,
+            _ => return Err(anyhow!("Unknown type {typ} of maple node")),
+        };
+
+        /* The slot after the last pivot ends at the max of node. It is
+         * also the case for the zero pivot after the first one, which
+         * is the end of data in the node. */
+        let mut offset = nr_pivots;
+        for i in 0..nr_pivots {
+            let pivot = read(node, typ, "pivot", i)?;
+            if index <= pivot || (pivot == 0 && i > 0) {
+                offset = i;
+                break;
+            }
+            min = pivot + 1;
+        }
+
+        /* The child of the internal node is the encoded node pointer
+         * without the root mark, so descend until the leaf. */
+        entry = read(node, typ, "slot", offset)?;
+        if typ == MAPLE_LEAF_64 || entry == 0 {
+            return Ok(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tree_tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const MAPLE_ROOT_NODE: u64 = 2;
+
+    fn enode(addr: u64, typ: u64) -> u64 {
+        addr | (typ << 3)
+    }
+
+    #[test]
+    fn test_maple_walk() -> Result<()> {
+        /* The arange_64 root has two leaves, for [0, 15] and [16, max].
+         * The second leaf has no pivot for its last slot. */
+        let mut nodes: HashMap<u64, (Vec<u64>, Vec<u64>)> = HashMap::new();
+        nodes.insert(
+            0x10000,
+            (
+                vec![15],
+                vec![enode(0x20000, MAPLE_LEAF_64), enode(0x30000, MAPLE_LEAF_64)],
+            ),
+        );
+        nodes.insert(
+            0x20000,
+            ((0..15).collect(), (0..16).map(|i| 0x1000 + i).collect()),
+        );
+        nodes.insert(0x30000, (vec![16, 17, 18], vec![0x2010, 0x2011, 0, 0x2013]));
+
+        let read = |node: u64, _typ: u64, member: &str, idx: u64| {
+            let (pivots, slots) = &nodes[&node];
+            let vals = if member == "pivot" { pivots } else { slots };
+            Ok(vals.get(idx as usize).copied().unwrap_or(0))
+        };
+        let root = enode(0x10000, MAPLE_ARANGE_64) | MAPLE_ROOT_NODE;
+
+        assert_eq!(maple_walk(root, 0, &read)?, 0x1000);
+        assert_eq!(maple_walk(root, 15, &read)?, 0x100f);
+        assert_eq!(maple_walk(root, 16, &read)?, 0x2010);
+        assert_eq!(maple_walk(root, 18, &read)?, 0);
+        assert_eq!(maple_walk(root, 19, &read)?, 0x2013);
+        assert_eq!(maple_walk(root, 1000, &read)?, 0x2013);
+
+        /* The tree with only one entry at index 0 */
+        assert_eq!(maple_walk(0xffff0000, 0, &read)?, 0xffff0000);
+        assert_eq!(maple_walk(0xffff0000, 1, &read)?, 0);
+
+        Ok(())
+    }
+}
@@ -2,18 +2,94 @@ use std::cmp::Ordering;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
+use anyhow::{anyhow, Result};
+
 pub const KSYM_INVALID: u8 = 0;
 pub const KSYM_FUNC: u8 = 1;
 pub const KSYM_DATA: u8 = 2;
+pub const KSYM_RODATA: u8 = 3;
+pub const KSYM_BSS: u8 = 4;
+/* The symbols which are not in the sections above, e.g. absolute */
+pub const KSYM_OTHER: u8 = 5;
+
+/* The kinds of symbol which can be watched as data */
+pub const KSYM_DATA_KINDS: &[u8] = &[KSYM_DATA, KSYM_RODATA, KSYM_BSS, KSYM_OTHER];
+
+/* Classify the symbol by the type letter of kallsyms. The lowercase
+ * is for the local symbol and the uppercase is for the global one. A
+ * weak symbol is "W" for function or "V" for object. */
+fn parse_kind(typ: &str) -> (u8, bool) {
+    match typ {
+        "t" | "T" => (KSYM_FUNC, false),
+        "w" | "W" => (KSYM_FUNC, true),
+        "d" | "D" => (KSYM_DATA, false),
+        "v" | "V" => (KSYM_DATA, true),
+        "r" | "R" => (KSYM_RODATA, false),
+        "b" | "B" => (KSYM_BSS, false),
+        _ => (KSYM_OTHER, false),
+    }
+}
+
+/* The module name which refers to the core kernel */
+pub const VMLINUX_MODULE: &str = "vmlinux";
+
+/* The symbol to compute the KASLR offset, which exists in any kernel */
+pub const KASLR_ANCHOR: &str = "_stext";
+
+/* Move the address by the KASLR offset. Only the kernel address is
+ * moved, the address of per-CPU symbols is the offset in the per-CPU
+ * area and it is not affected by KASLR. */
+pub fn relocate_addr(addr: usize, offset: usize) -> usize {
+    if (addr as isize) < 0 {
+        addr.wrapping_add(offset)
+    } else {
+        addr
+    }
+}
+
+/* Split the symbol in "module:symbol" format */
+pub fn split_module(sym: &str) -> (Option<&str>, &str) {
+    match sym.split_once(':') {
+        Some((module, name)) => (Some(module), name),
+        None => (None, sym),
+    }
+}
 
 #[derive(Debug)]
-struct Ksym {
-    name: String,
-    kind: u8,
-    addr: usize,
+pub struct Ksym {
+    pub name: String,
+    pub kind: u8,
+    pub weak: bool,
+    pub addr: usize,
+    /* The module where the symbol is from, or None for vmlinux */
+    pub module: Option<String>,
 }
 
 impl Ksym {
+    pub fn kind_name(&self) -> &'static str {
+        match (self.kind, self.weak) {
+            (KSYM_FUNC, false) => "func",
+            (KSYM_FUNC, true) => "wfunc",
+            (KSYM_DATA, false) => "data",
+            (KSYM_DATA, true) => "wdata",
+            (KSYM_RODATA, _) => "rodata",
+            (KSYM_BSS, _) => "bss",
+            _ => "other",
+        }
+    }
+
+    /* Describe the address in "symbol+offset [module]" format */
+    pub fn describe(&self, offset: usize) -> String {
+        let mut desc = self.name.clone();
+        if offset != 0 {
+            desc.push_str(&format!("+{offset:#x}"));
+        }
+        if let Some(module) = &self.module {
+            desc.push_str(&format!(" [{module}]"));
+        }
+        desc
+    }
+
     fn by_name_cmp(&self, other: &Ksym) -> Ordering {
         if self.kind != other.kind {
             return self.kind.cmp(&other.kind);
@@ -28,22 +104,34 @@ impl Ksym {
  * information quickly. */
 pub struct KSymResolver {
     syms: Vec<Ksym>,
+    /* The index of syms in address order */
+    by_addr: Vec<usize>,
 }
 
 impl KSymResolver {
     pub fn new() -> Self {
-        let f = File::open("/proc/kallsyms").expect("/proc/kallsyms is needed for KSymResolver");
-        let mut reader = BufReader::new(f);
+        Self::from_file("/proc/kallsyms").expect("/proc/kallsyms is needed for KSymResolver")
+    }
+
+    /* Load the symbols from the file in the format of kallsyms, which
+     * can be a snapshot of /proc/kallsyms or System.map. */
+    pub fn from_file(path: &str) -> Result<Self> {
+        let f = File::open(path).map_err(|e| anyhow!("Failed to open {path}: {e}"))?;
+        Self::from_reader(BufReader::new(f)).map_err(|e| anyhow!("Invalid symbol file {path}: {e}"))
+    }
 
+    fn from_reader<R: BufRead>(mut reader: R) -> Result<Self> {
         let mut line = String::new();
         let mut syms = Vec::new();
+        let mut lineno = 0;
 
         loop {
             line.clear();
-            let sz = reader.read_line(&mut line).unwrap();
+            let sz = reader.read_line(&mut line)?;
             if sz == 0 {
                 break;
             }
+            lineno += 1;
 
             let tokens = line.split_whitespace().collect::<Vec<_>>();
             if tokens.len() < 3 {
@@ -52,36 +140,249 @@ impl KSymResolver {
 
             let (addr, kind, func) = (tokens[0], tokens[1], tokens[2]);
             let name = func.to_owned();
-            let addr = usize::from_str_radix(addr, 16).unwrap();
-            if addr == 0 {
-                continue;
-            }
-            let kind = if kind == "t" || kind == "T" {
-                KSYM_FUNC
-            } else {
-                KSYM_DATA
+            let module = tokens
+                .get(3)
+                .map(|m| m.trim_start_matches('[').trim_end_matches(']').to_owned());
+            let addr = usize::from_str_radix(addr, 16)
+                .map_err(|e| anyhow!("Invalid address {addr} at line {lineno}: {e}"))?;
+            let (kind, weak) = parse_kind(kind);
+            let sym = Ksym {
+                name,
+                kind,
+                weak,
+                addr,
+                module,
             };
-            let sym = Ksym { name, kind, addr };
             syms.push(sym);
         }
 
+        /* All the addresses are zero if they are hidden by kptr_restrict.
+         * Otherwise the zero address is real, e.g. __per_cpu_start of
+         * x86_64 with the zero-based per-CPU area. */
+        if syms.iter().all(|sym| sym.addr == 0) {
+            syms.clear();
+        }
+
+        Ok(Self::from_syms(syms))
+    }
+
+    fn from_syms(mut syms: Vec<Ksym>) -> Self {
         syms.sort_by(|a, b| a.by_name_cmp(&b));
 
-        Self { syms }
+        let mut by_addr = (0..syms.len()).collect::<Vec<_>>();
+        by_addr.sort_by_key(|&idx| syms[idx].addr);
+
+        Self { syms, by_addr }
     }
 
-    pub fn find_ksym(&self, sym: &str, kind: u8) -> Option<usize> {
-        let sym = Ksym {
-            name: sym.to_owned(),
-            kind: kind,
-            addr: 0,
-        };
-        let symidx = self.syms.binary_search_by(|a| a.by_name_cmp(&sym));
+    /* The address of the anchor symbol to compute the KASLR offset */
+    pub fn anchor(&self) -> Result<usize> {
+        let sym = format!("{VMLINUX_MODULE}:{KASLR_ANCHOR}");
+        Ok(self.find_ksym(&sym, &[KSYM_FUNC])?.addr)
+    }
+
+    /* Move the symbols by the KASLR offset. The symbols of modules are
+     * dropped because they are not moved with the kernel, so they are
+     * only valid from the running kernel. */
+    pub fn relocate(self, offset: usize) -> Self {
+        let syms = self
+            .syms
+            .into_iter()
+            .filter(|sym| sym.module.is_none())
+            .map(|sym| Ksym {
+                addr: relocate_addr(sym.addr, offset),
+                ..sym
+            })
+            .collect();
+
+        Self::from_syms(syms)
+    }
+
+    /* Find the symbol which the address belongs to, and the offset of
+     * the address from the symbol. Because the size of symbol is unknown
+     * from kallsyms, we assume the symbol ends at the next symbol in the
+     * same module. */
+    pub fn find_addr(&self, addr: usize) -> Option<(&Ksym, usize)> {
+        let pos = self
+            .by_addr
+            .partition_point(|&idx| self.syms[idx].addr <= addr);
+        if pos == 0 {
+            return None;
+        }
 
-        if let Ok(idx) = symidx {
-            return Some(self.syms[idx].addr);
+        let sym = &self.syms[self.by_addr[pos - 1]];
+        let next = self.by_addr.get(pos).map(|&idx| &self.syms[idx])?;
+        if next.module != sym.module {
+            return None;
         }
 
-        None
+        Some((sym, addr - sym.addr))
+    }
+
+    /* Compute the KASLR offset from the linked address of the anchor
+     * symbol, e.g. the one in vmlinux. The offset should be added to the
+     * linked address to get the address in the running kernel. */
+    pub fn kaslr_offset(&self, anchor: usize) -> Result<usize> {
+        let addr = self
+            .anchor()
+            .map_err(|e| anyhow!("Failed to compute KASLR offset: {e}"))?;
+
+        Ok(addr.wrapping_sub(anchor))
+    }
+
+    /* Find the symbols whose name is matched, in address order */
+    pub fn search<F: Fn(&str) -> bool>(&self, matches: F) -> Vec<&Ksym> {
+        let mut syms = self
+            .syms
+            .iter()
+            .filter(|s| matches(&s.name))
+            .collect::<Vec<_>>();
+        syms.sort_by_key(|s| s.addr);
+        syms
+    }
+
+    /* Find the symbol in one of the kinds, which can be prefixed by
+     * the module name in "module:symbol" format. */
+    pub fn find_ksym(&self, sym: &str, kinds: &[u8]) -> Result<&Ksym> {
+        let (module, name) = split_module(sym);
+
+        let mut candidates = Vec::new();
+        for &kind in kinds {
+            let target = Ksym {
+                name: name.to_owned(),
+                kind: kind,
+                weak: false,
+                addr: 0,
+                module: None,
+            };
+            let start = self
+                .syms
+                .partition_point(|a| a.by_name_cmp(&target) == Ordering::Less);
+            let end = self
+                .syms
+                .partition_point(|a| a.by_name_cmp(&target) != Ordering::Greater);
+
+            candidates.extend(self.syms[start..end].iter().filter(|s| match module {
+                Some(VMLINUX_MODULE) => s.module.is_none(),
+                Some(module) => s.module.as_deref() == Some(module),
+                None => true,
+            }));
+        }
+
+        match candidates.len() {
+            0 => Err(anyhow!("Failed to get address of symbol {sym}")),
+            1 => Ok(candidates[0]),
+            _ => {
+                let list = candidates
+                    .iter()
+                    .map(|s| {
+                        let module = s.module.as_deref().unwrap_or(VMLINUX_MODULE);
+                        format!("{module}:{} at {:#x}", s.name, s.addr)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                Err(anyhow!("Ambiguous symbol {sym}, candidates are {list}"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod ksym_tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/ksym/{name}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[test]
+    fn test_find_ksym() -> Result<()> {
+        let kresolver = KSymResolver::from_file(&fixture("kallsyms"))?;
+
+        let sym = kresolver.find_ksym("scheduler_tick", &[KSYM_FUNC])?;
+        assert_eq!(sym.addr, 0xffffffff9a003000);
+
+        let sym = kresolver.find_ksym("arch_cpu_idle", &[KSYM_FUNC])?;
+        assert_eq!(sym.kind_name(), "wfunc");
+
+        let sym = kresolver.find_ksym("linux_banner", KSYM_DATA_KINDS)?;
+        assert_eq!(sym.kind, KSYM_RODATA);
+
+        let sym = kresolver.find_ksym("some_bss", KSYM_DATA_KINDS)?;
+        assert_eq!(sym.kind, KSYM_BSS);
+
+        /* The symbol of the module is not confused with vmlinux */
+        assert!(kresolver.find_ksym("jiffies_64", KSYM_DATA_KINDS).is_err());
+        let sym = kresolver.find_ksym("vmlinux:jiffies_64", KSYM_DATA_KINDS)?;
+        assert_eq!(sym.addr, 0xffffffff9b800000);
+        let sym = kresolver.find_ksym("dummy:jiffies_64", KSYM_DATA_KINDS)?;
+        assert_eq!(sym.addr, 0xffffffffc0102008);
+
+        assert!(kresolver.find_ksym("__func__.0", KSYM_DATA_KINDS).is_err());
+        assert!(kresolver
+            .find_ksym("scheduler_tick", KSYM_DATA_KINDS)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_addr() -> Result<()> {
+        let kresolver = KSymResolver::from_file(&fixture("kallsyms"))?;
+
+        let (sym, offset) = kresolver.find_addr(0xffffffff9b800004).unwrap();
+        assert_eq!(sym.describe(offset), "jiffies_64+0x4");
+
+        let (sym, offset) = kresolver.find_addr(0xffffffffc0102000).unwrap();
+        assert_eq!(sym.describe(offset), "mod_data [dummy]");
+
+        /* The address is out of the known symbols */
+        assert!(kresolver.find_addr(0xffffffff80000000).is_none());
+        assert!(kresolver.find_addr(0xffffffffc0200000).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_zero_addr() -> Result<()> {
+        let sysmap = KSymResolver::from_file(&fixture("System.map"))?;
+        let sym = sysmap.find_ksym("__per_cpu_start", KSYM_DATA_KINDS)?;
+        assert_eq!(sym.addr, 0);
+
+        /* The addresses are hidden by kptr_restrict */
+        let hidden = KSymResolver::from_reader(
+            "0000000000000000 T _stext\n0000000000000000 D jiffies_64\n".as_bytes(),
+        )?;
+        assert!(hidden.find_ksym("jiffies_64", KSYM_DATA_KINDS).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_relocate() -> Result<()> {
+        let running = KSymResolver::from_file(&fixture("kallsyms"))?;
+        let sysmap = KSymResolver::from_file(&fixture("System.map"))?;
+
+        let offset = running.kaslr_offset(sysmap.anchor()?)?;
+        assert_eq!(offset, 0x19000000);
+
+        let sysmap = sysmap.relocate(offset);
+        let sym = sysmap.find_ksym("jiffies_64", KSYM_DATA_KINDS)?;
+        assert_eq!(sym.addr, 0xffffffff9b800000);
+        let sym = sysmap.find_ksym("setup_arch", &[KSYM_FUNC])?;
+        assert_eq!(sym.addr, 0xffffffff9a001000);
+        /* The per-CPU symbol is not moved */
+        let sym = sysmap.find_ksym("fixed_percpu_data", KSYM_DATA_KINDS)?;
+        assert_eq!(sym.addr, 0x1000);
+        let sym = sysmap.find_ksym("__per_cpu_start", KSYM_DATA_KINDS)?;
+        assert_eq!(sym.addr, 0);
+
+        /* The symbols of modules can't be relocated */
+        let snapshot = running.relocate(0x1000);
+        assert!(snapshot
+            .find_ksym("dummy:mod_data", KSYM_DATA_KINDS)
+            .is_err());
+
+        Ok(())
     }
 }
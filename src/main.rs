@@ -1,185 +1,936 @@
+use std::collections::{BTreeMap, HashMap};
 use std::mem::MaybeUninit;
+use std::process::ExitCode;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::bump_memlock_rlimit::*;
+use crate::config::Config;
+use crate::hotplug::CpuTracker;
 use crate::kexpr::*;
-use crate::ksym::{KSymResolver, KSYM_FUNC};
+use crate::ksym::{
+    relocate_addr, split_module, KSymResolver, KASLR_ANCHOR, KSYM_DATA_KINDS, KSYM_FUNC,
+    KSYM_RODATA, VMLINUX_MODULE,
+};
 use crate::msg::*;
-use crate::perf::attach_breakpoint;
-use crate::utils::hexstr2int;
+use crate::perf::{attach_breakpoint, BpScope};
+use crate::symexpr::{eval_symexpr, is_symbol};
+use crate::syms::list_syms;
+use crate::utils::{get_possible_cpus, get_threads, parse_duration, CpuList};
+use crate::watch::*;
+use crate::workload::Workload;
 
-use ksym::KSYM_DATA;
 use libbpf_rs::skel::*;
-use libbpf_rs::RingBufferBuilder;
+use libbpf_rs::{Link, MapCore, MapFlags, ProgramMut, RingBufferBuilder};
 
 use anyhow::{anyhow, Result};
-use clap::Parser;
+use clap::{ArgGroup, Parser, Subcommand};
+use libc::{c_int, pid_t};
 
-use perf_event_open_sys::bindings::{
-    HW_BREAKPOINT_R, HW_BREAKPOINT_RW, HW_BREAKPOINT_W, HW_BREAKPOINT_X,
-};
+use perf_event_open_sys::bindings::{HW_BREAKPOINT_RW, HW_BREAKPOINT_W, HW_BREAKPOINT_X};
 
 use blazesym::inspect;
 use blazesym::inspect::Inspector;
 
 mod bump_memlock_rlimit;
+mod config;
+mod hotplug;
 mod kexpr;
 mod ksym;
 mod msg;
 mod perf;
+mod symexpr;
+mod syms;
 mod utils;
+mod watch;
+mod workload;
 
 #[path = "../bpf/.output/kmemsnoop.skel.rs"]
 #[cfg_attr(rustfmt, rustfmt_skip)]
 mod kmemsnoop;
 use kmemsnoop::*;
 
-fn vmlinux2addr(sym: &str, vmlinux: &str) -> Result<usize> {
+/* Get the address and the size of symbol from vmlinux */
+fn vmlinux2sym(sym: &str, vmlinux: &str) -> Result<(usize, Option<u64>)> {
+    let (_, name) = split_module(sym);
     let src = inspect::Source::Elf(inspect::Elf::new(vmlinux));
     let inspector = Inspector::new();
-    let results = inspector.lookup(&src, &[sym])?;
+    let mut results = inspector.lookup(&src, &[name, KASLR_ANCHOR])?;
 
+    let anchor = results.pop().unwrap_or_default();
     let results = results.into_iter().flatten().collect::<Vec<_>>();
 
-    if results.len() != 1 {
+    if results.is_empty() {
         return Err(anyhow!(format!("Failed to get address of symbol {sym}")));
     }
 
-    let addr = results[0].addr as usize;
+    if results.len() > 1 {
+        let list = results
+            .iter()
+            .map(|r| format!("{} at {:#x}", r.name, r.addr))
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(anyhow!("Ambiguous symbol {sym}, candidates are {list}"));
+    }
+
+    /* The address in vmlinux is the linked one, so it should be moved
+     * by the KASLR offset of running kernel. */
+    let anchor = anchor
+        .first()
+        .ok_or(anyhow!("Failed to find {KASLR_ANCHOR} in {vmlinux}"))?;
+    let offset = KSymResolver::new().kaslr_offset(anchor.addr as usize)?;
+    let addr = relocate_addr(results[0].addr as usize, offset);
+    let size = results[0].size.map(|sz| sz as u64);
 
-    Ok(addr)
+    Ok((addr, size))
 }
 
-#[derive(clap::ValueEnum, Clone)]
-enum BpType {
-    R1,
-    W1,
-    RW1,
-    X1,
-    R2,
-    W2,
-    RW2,
-    X2,
-    R4,
-    W4,
-    RW4,
-    X4,
-    R8,
-    W8,
-    RW8,
-    X8,
+/* The kinds of symbol which can be watched by the breakpoint */
+fn ksym_kinds(bp: u32) -> &'static [u8] {
+    match bp {
+        HW_BREAKPOINT_X => &[KSYM_FUNC],
+        _ => KSYM_DATA_KINDS,
+    }
 }
 
 fn ksym2addr(sym: &str, bp: u32) -> Result<usize> {
     let kresolver = KSymResolver::new();
 
-    let sym_typ = match bp {
-        HW_BREAKPOINT_X => KSYM_FUNC,
-        _ => KSYM_DATA,
-    };
-
     kresolver
-        .find_ksym(sym, sym_typ)
-        .ok_or(anyhow!(format!("Failed to get address of symbol {sym}")))
+        .find_ksym(sym, ksym_kinds(bp))
+        .map(|ksym| ksym.addr)
+}
+
+/* Load the symbols from System.map or the saved kallsyms, which are
+ * moved by the KASLR offset of running kernel. */
+fn load_symbols(path: &str) -> Result<KSymResolver> {
+    let symbols = KSymResolver::from_file(path)?;
+    let offset = KSymResolver::new().kaslr_offset(symbols.anchor()?)?;
+
+    Ok(symbols.relocate(offset))
+}
+
+#[derive(Subcommand)]
+enum SubCmd {
+    #[command(about = "list the kernel symbols which match the pattern")]
+    Syms {
+        #[arg(help = "glob pattern of the symbol name, e.g. 'jiffies*'")]
+        pattern: String,
+
+        #[arg(short, long, help = "use regex instead of glob for the pattern")]
+        regex: bool,
+
+        #[arg(short, long, help = "vmlinux path to get the size of symbols")]
+        vmlinux: Option<String>,
+    },
 }
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(group(ArgGroup::new("kexpr").multiple(false)))]
 struct Cli {
-    #[arg(value_enum, help = "type of the watchpoint")]
-    bp: BpType,
+    #[command(subcommand)]
+    subcmd: Option<SubCmd>,
+
+    #[arg(
+        requires = "expr",
+        value_parser = parse_bp_type,
+        help = "type of the watchpoint, e.g. rw4 [possible kinds: r, w, rw, x]"
+    )]
+    bp: Option<BpType>,
 
     #[arg(help = "expression of watchpoint(kernel symbol or addess by default)")]
-    expr: String,
+    expr: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "BP:EXPR",
+        value_parser = parse_watch_arg,
+        help = "add a watchpoint, can be specified multiple times"
+    )]
+    watch: Vec<WatchArg>,
+
+    #[arg(
+        long,
+        requires = "bp",
+        value_parser = parse_bp_len,
+        help = "length of the watchpoint in bytes, or 'auto' for the size of object"
+    )]
+    len: Option<BpLen>,
+
+    #[arg(short, long, help = "load the watch session from the TOML file")]
+    config: Option<String>,
+
+    #[arg(long, help = "only watch the task with the tid and its children")]
+    task: Option<pid_t>,
+
+    #[arg(
+        long,
+        conflicts_with = "task",
+        help = "only watch the threads of the process and their children"
+    )]
+    tgid: Option<pid_t>,
+
+    #[arg(
+        long,
+        value_parser = CpuList::parse,
+        conflicts_with_all = ["task", "tgid"],
+        help = "only watch on the CPUs in the list, e.g. 0-3,8"
+    )]
+    cpus: Option<CpuList>,
+
+    #[arg(long, help = "stop after the watchpoints are hit for N times")]
+    count: Option<u64>,
+
+    #[arg(
+        long,
+        value_parser = parse_duration,
+        help = "stop after the duration, e.g. 30s"
+    )]
+    duration: Option<Duration>,
+
+    #[arg(
+        long,
+        conflicts_with = "count",
+        help = "stop on the first hit of the watchpoints"
+    )]
+    exit_on_hit: bool,
+
+    #[arg(
+        long,
+        requires = "command",
+        conflicts_with_all = ["task", "tgid", "cpus"],
+        help = "only watch the command and its children"
+    )]
+    child_only: bool,
+
+    #[arg(
+        long,
+        requires = "command",
+        help = "run the command as the user who invokes sudo instead of root"
+    )]
+    user: bool,
+
+    #[arg(
+        long,
+        requires = "command",
+        help = "exit with 2 if any watchpoint is hit, instead of the status of the command"
+    )]
+    hit_status: bool,
+
+    #[arg(
+        long,
+        help = "resolve and check the watchpoints, print them without arming"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        value_parser = parse_format,
+        help = "output format of the events, 'text' or 'json'"
+    )]
+    format: Option<OutputFormat>,
 
-    #[arg(short, long, help = "vmlinux path of running kernel(need nokaslr)")]
+    #[arg(last = true, help = "the command to run under watch")]
+    command: Vec<String>,
+
+    #[arg(short, long, help = "vmlinux path of running kernel")]
     vmlinux: Option<String>,
 
-    #[arg(long, help = "kexpr: use 'struct task_struct' from pid")]
+    #[arg(
+        long,
+        conflicts_with = "vmlinux",
+        help = "System.map or saved kallsyms to get the address of symbols"
+    )]
+    symbols: Option<String>,
+
+    #[arg(
+        long,
+        group = "kexpr",
+        help = "kexpr: use 'struct task_struct' from pid"
+    )]
     pid_task: Option<u64>,
 
-    #[arg(long, help = "kexpr: 'struct pci_dev' from the device name")]
+    #[arg(
+        long,
+        group = "kexpr",
+        help = "kexpr: 'struct pci_dev' from the device name"
+    )]
     pci_dev: Option<String>,
 
-    #[arg(long, help = "kexpr: 'struct usb_device' from the device name")]
+    #[arg(
+        long,
+        group = "kexpr",
+        help = "kexpr: 'struct usb_device' from the device name"
+    )]
     usb_dev: Option<String>,
 
-    #[arg(long, help = "kexpr: 'struct platform_device' from the device name")]
+    #[arg(
+        long,
+        group = "kexpr",
+        help = "kexpr: 'struct platform_device' from the device name"
+    )]
     plat_dev: Option<String>,
+
+    #[arg(
+        long,
+        group = "kexpr",
+        help = "kexpr: the global variable from the symbol name"
+    )]
+    global: Option<String>,
+
+    #[arg(
+        long,
+        group = "kexpr",
+        value_parser = parse_pid_fd,
+        help = "kexpr: 'struct file' from the file descriptor as pid:fd"
+    )]
+    file: Option<(u64, u64)>,
+
+    #[arg(
+        long,
+        group = "kexpr",
+        value_parser = parse_pid_fd,
+        help = "kexpr: 'struct socket' from the file descriptor as pid:fd"
+    )]
+    sock: Option<(u64, u64)>,
+
+    #[arg(long, group = "kexpr", help = "kexpr: 'struct inode' from the path")]
+    inode: Option<String>,
+
+    #[arg(
+        long,
+        group = "kexpr",
+        help = "kexpr: 'struct super_block' from the path on the filesystem"
+    )]
+    sb: Option<String>,
+
+    #[arg(
+        long,
+        group = "kexpr",
+        help = "kexpr: 'struct module' from the module name"
+    )]
+    module: Option<String>,
+
+    #[arg(
+        long,
+        group = "kexpr",
+        help = "kexpr: 'struct irq_desc' from the IRQ number"
+    )]
+    irq: Option<u64>,
+
+    #[arg(
+        long,
+        group = "kexpr",
+        help = "kexpr: 'struct net_device' from the interface name"
+    )]
+    net_dev: Option<String>,
+
+    #[arg(
+        long,
+        requires = "net_dev",
+        help = "kexpr: find NET_DEV in the network namespace of the pid"
+    )]
+    netns_pid: Option<u64>,
+
+    #[arg(
+        long,
+        requires = "net_dev",
+        help = "kexpr: use netdev_priv() of NET_DEV as the struct type"
+    )]
+    net_priv: Option<String>,
+
+    #[arg(
+        long,
+        value_parser = parse_percpu,
+        conflicts_with_all = ["task", "tgid", "child_only"],
+        help = "watch the instance of per-CPU variable on the CPU, or 'all'"
+    )]
+    percpu: Option<PerCpu>,
+}
+
+impl Cli {
+    /* At most one of the kexpr options can be specified, which is
+     * checked by the "kexpr" group. */
+    fn kexpr_root(&self) -> Option<KexprRoot> {
+        if let Some(pid) = self.pid_task {
+            return Some(KexprRoot::Task(pid));
+        }
+
+        if let Some(pci_dev) = &self.pci_dev {
+            return Some(KexprRoot::PciDev(pci_dev.clone()));
+        }
+
+        if let Some(usb_dev) = &self.usb_dev {
+            return Some(KexprRoot::UsbDev(usb_dev.clone()));
+        }
+
+        if let Some(plat_dev) = &self.plat_dev {
+            return Some(KexprRoot::PlatDev(plat_dev.clone()));
+        }
+
+        if let Some(global) = &self.global {
+            return Some(KexprRoot::Global(global.clone()));
+        }
+
+        if let Some((pid, fd)) = self.file {
+            return Some(KexprRoot::File(pid, fd));
+        }
+
+        if let Some((pid, fd)) = self.sock {
+            return Some(KexprRoot::Sock(pid, fd));
+        }
+
+        if let Some(inode) = &self.inode {
+            return Some(KexprRoot::Inode(inode.clone()));
+        }
+
+        if let Some(sb) = &self.sb {
+            return Some(KexprRoot::Sb(sb.clone()));
+        }
+
+        if let Some(module) = &self.module {
+            return Some(KexprRoot::Module(module.clone()));
+        }
+
+        if let Some(irq) = self.irq {
+            return Some(KexprRoot::Irq(irq));
+        }
+
+        if let Some(net_dev) = &self.net_dev {
+            return Some(KexprRoot::NetDev {
+                name: net_dev.clone(),
+                netns_pid: self.netns_pid,
+                priv_type: self.net_priv.clone(),
+            });
+        }
+
+        None
+    }
+
+    /* The options on the command line take precedence over the
+     * ones in config file. */
+    fn apply_config(&mut self, config: Config) {
+        self.watch.extend(config.watches);
+        if self.vmlinux.is_none() && self.symbols.is_none() {
+            self.vmlinux = config.vmlinux;
+            self.symbols = config.symbols;
+        }
+        if self.task.is_none() && self.tgid.is_none() && self.cpus.is_none() {
+            self.task = config.task;
+            self.tgid = config.tgid;
+            self.cpus = config.cpus.map(CpuList);
+        }
+        if self.count.is_none() && !self.exit_on_hit {
+            self.count = config.count;
+            self.exit_on_hit = config.exit_on_hit;
+        }
+        if self.duration.is_none() {
+            self.duration = config.duration;
+        }
+        if self.percpu.is_none() {
+            self.percpu = config.percpu;
+        }
+        if self.format.is_none() {
+            self.format = config.format;
+        }
+    }
+
+    /* clap only checks the conflicts on the command line, so check
+     * them again after the config file is applied. This should be
+     * the same as conflicts_with of the options. */
+    fn check_conflicts(&self) -> Result<()> {
+        let percpu_all = matches!(self.percpu, Some(PerCpu::All))
+            || self
+                .watch
+                .iter()
+                .any(|w| matches!(w.percpu, Some(PerCpu::All)));
+        let opts = [
+            ("task", self.task.is_some()),
+            ("tgid", self.tgid.is_some()),
+            ("cpus", self.cpus.is_some()),
+            ("count", self.count.is_some()),
+            ("exit-on-hit", self.exit_on_hit),
+            ("child-only", self.child_only),
+            ("vmlinux", self.vmlinux.is_some()),
+            ("symbols", self.symbols.is_some()),
+            ("percpu", self.percpu.is_some() || percpu_all),
+        ];
+        let conflicts = [
+            ("tgid", "task"),
+            ("cpus", "task"),
+            ("cpus", "tgid"),
+            ("exit-on-hit", "count"),
+            ("child-only", "task"),
+            ("child-only", "tgid"),
+            ("child-only", "cpus"),
+            ("symbols", "vmlinux"),
+            ("percpu", "task"),
+            ("percpu", "tgid"),
+            ("percpu", "child-only"),
+        ];
+
+        let is_set = |name: &str| opts.iter().any(|&(opt, set)| opt == name && set);
+        for (a, b) in conflicts {
+            if is_set(a) && is_set(b) {
+                return Err(anyhow!("{a} can't be specified with {b}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /* The CPUs selected to be watched, or None for all the CPUs */
+    fn cpus(&self) -> Option<Vec<c_int>> {
+        self.cpus.as_ref().map(|list| list.0.clone())
+    }
+
+    /* Stop after the watchpoints are hit for this times */
+    fn max_hits(&self) -> Option<u64> {
+        if self.exit_on_hit {
+            return Some(1);
+        }
+
+        self.count
+    }
+
+    /* The scope of breakpoints if they are limited to tasks */
+    fn task_scope(&self) -> Result<Option<BpScope>> {
+        if let Some(tid) = self.task {
+            return Ok(Some(BpScope::Tasks(vec![tid])));
+        }
+
+        if let Some(pid) = self.tgid {
+            return Ok(Some(BpScope::Tasks(get_threads(pid)?)));
+        }
+
+        Ok(None)
+    }
 }
 
-fn parse_addr(cli: &Cli, bp_type: u32) -> Result<usize> {
-    let expr = &cli.expr;
-    let pid_task = &cli.pid_task;
-    let pci_dev = &cli.pci_dev;
-    let usb_dev = &cli.usb_dev;
-    let plat_dev = &cli.plat_dev;
-    let vmlinux = &cli.vmlinux;
+/* Get the size of symbol from vmlinux if it is given, otherwise from
+ * the type information of running kernel. */
+fn symbol_size(cli: &Cli, sym: &str) -> Option<u64> {
+    let (module, name) = split_module(sym);
+    if let (Some(vmlinux), None | Some(VMLINUX_MODULE)) = (&cli.vmlinux, module) {
+        return vmlinux2sym(sym, vmlinux).ok().and_then(|(_, size)| size);
+    }
+
+    kexpr::sym_size(name).ok()
+}
 
-    /* Use kexpr if special option is specified.
-     * FIXME: If several kexpr option is specified, kmemsnoop
-     * only takes one of it by order. Do we want to avoid this? */
-    if let Some(pid) = *pid_task {
-        return task_kexpr2addr(pid, &expr);
+/* Use vmlinux or the symbol file to know the address by symbol if it
+ * is given */
+fn sym2addr(cli: &Cli, symbols: Option<&KSymResolver>, sym: &str, bp: u32) -> Result<usize> {
+    match (&cli.vmlinux, symbols, split_module(sym)) {
+        /* The symbols of modules can only be found from kallsyms */
+        (_, _, (Some(module), _)) if module != VMLINUX_MODULE => ksym2addr(sym, bp),
+        (Some(vmlinux), _, _) => vmlinux2sym(sym, vmlinux).map(|(addr, _)| addr),
+        (None, Some(symbols), _) => symbols.find_ksym(sym, ksym_kinds(bp)).map(|ksym| ksym.addr),
+        _ => ksym2addr(sym, bp),
     }
+}
+
+/* Resolve the address of watch. The size of object at the address is
+ * also returned if it is known, which is for kexpr or a plain symbol. */
+fn parse_addr(cli: &Cli, arg: &WatchArg) -> Result<(usize, Option<u64>)> {
+    let expr = &arg.expr;
 
-    if let Some(pci_dev) = pci_dev {
-        return pcidev_kexpr2addr(&pci_dev, &expr);
+    /* Use kexpr if special option is specified. */
+    if let Some(root) = arg.root.clone().or_else(|| cli.kexpr_root()) {
+        let value = eval_kexpr(&root, expr)?;
+        return Ok((value.addr, value.size));
     }
 
-    if let Some(usb_dev) = usb_dev {
-        return usbdev_kexpr2addr(&usb_dev, &expr);
+    let symbols = cli.symbols.as_deref().map(load_symbols).transpose()?;
+    let lookup = |sym: &str| sym2addr(cli, symbols.as_ref(), sym, arg.bp_type);
+
+    let addr =
+        eval_symexpr(expr, &lookup).map_err(|e| anyhow!("Invalid expression {expr}: {e}"))?;
+
+    /* The size is only needed for the auto length */
+    let mut size = None;
+    if arg.bp_len == BpLen::Auto && is_symbol(expr) {
+        size = symbol_size(cli, expr.trim());
     }
 
-    if let Some(plat_dev) = plat_dev {
-        return platdev_kexpr2addr(&plat_dev, &expr);
+    Ok((addr, size))
+}
+
+fn parse_watches(cli: &Cli) -> Result<Vec<Watch>> {
+    let mut args = Vec::new();
+    if let (Some(bp), Some(expr)) = (&cli.bp, &cli.expr) {
+        args.push(WatchArg {
+            bp_type: bp.kind,
+            bp_len: cli.len.unwrap_or(bp.len),
+            expr: expr.clone(),
+            root: None,
+            percpu: None,
+        });
     }
+    args.extend(cli.watch.iter().cloned());
 
-    if let Ok(addr) = hexstr2int(expr) {
-        return Ok(addr);
+    if args.is_empty() {
+        return Err(anyhow!("No watchpoint is specified"));
     }
 
-    /* Use vmlinux to know the address by symbol */
-    if let Some(vmlinux) = vmlinux {
-        return vmlinux2addr(&expr, &vmlinux);
+    let mut watches: Vec<Watch> = Vec::new();
+    for (id, arg) in args.into_iter().enumerate() {
+        let root = arg.root.clone().or_else(|| cli.kexpr_root());
+        let percpu = arg.percpu.or(cli.percpu);
+        let instances = match percpu {
+            Some(percpu) => percpu_instances(cli, &arg, root.as_ref(), percpu)?,
+            None => {
+                let (addr, size) = parse_addr(cli, &arg)?;
+                vec![(None, addr, size)]
+            }
+        };
+
+        for (cpu, addr, size) in instances {
+            let bp_len = match arg.bp_len {
+                BpLen::Bytes(len) => len,
+                BpLen::Auto => {
+                    let len = auto_bp_len(arg.bp_type, size).ok_or(anyhow!(
+                        "Failed to know the size of {}, please specify the length",
+                        arg.expr
+                    ))?;
+                    println!(
+                        "Watchpoint {id} uses {}{len} for {}",
+                        bp_kind_name(arg.bp_type),
+                        arg.expr
+                    );
+                    len
+                }
+            };
+
+            watches.push(Watch {
+                id: id as u32,
+                expr: arg.expr.clone(),
+                bp_type: arg.bp_type,
+                bp_len,
+                addr,
+                auto_len: arg.bp_len == BpLen::Auto,
+                root: root.clone(),
+                percpu: cpu,
+                pin_cpu: matches!(percpu, Some(PerCpu::All)),
+            });
+        }
     }
 
-    ksym2addr(&expr, bp_type)
+    Ok(watches)
 }
 
-fn parse_bp(cli: &Cli) -> (u32, u64) {
-    let bp = &cli.bp;
+/* The bounds of the per-CPU section, where the per-CPU variables are */
+const PERCPU_START: &str = "__per_cpu_start";
+const PERCPU_END: &str = "__per_cpu_end";
 
-    let bp_len = match bp {
-        BpType::R1 | BpType::W1 | BpType::RW1 | BpType::X1 => 1,
-        BpType::R2 | BpType::W2 | BpType::RW2 | BpType::X2 => 2,
-        BpType::R4 | BpType::W4 | BpType::RW4 | BpType::X4 => 4,
-        BpType::R8 | BpType::W8 | BpType::RW8 | BpType::X8 => 8,
+/* Resolve the instances of per-CPU variable as (cpu, address, size).
+ * The expression is a per-CPU symbol, or a kexpr on the per-CPU
+ * global variable. */
+fn percpu_instances(
+    cli: &Cli,
+    arg: &WatchArg,
+    root: Option<&KexprRoot>,
+    percpu: PerCpu,
+) -> Result<Vec<(Option<c_int>, usize, Option<u64>)>> {
+    let possible = get_possible_cpus()?;
+    let cpus = match percpu {
+        PerCpu::Cpu(cpu) if possible.contains(&cpu) => vec![cpu],
+        PerCpu::Cpu(cpu) => return Err(anyhow!("CPU {cpu} is not a possible CPU")),
+        /* Resolve the possible CPUs, so the CPU which becomes online
+         * later can be watched too. */
+        PerCpu::All => {
+            let mut cpus = possible;
+            if let Some(CpuList(filter)) = &cli.cpus {
+                cpus.retain(|cpu| filter.contains(cpu));
+            }
+            cpus
+        }
     };
-    let bp_type = match bp {
-        BpType::X1 | BpType::X2 | BpType::X4 | BpType::X8 => HW_BREAKPOINT_X,
-        BpType::R1 | BpType::R2 | BpType::R4 | BpType::R8 => HW_BREAKPOINT_R,
-        BpType::W1 | BpType::W2 | BpType::W4 | BpType::W8 => HW_BREAKPOINT_W,
-        BpType::RW1 | BpType::RW2 | BpType::RW4 | BpType::RW8 => HW_BREAKPOINT_RW,
+
+    /* The address should be in the per-CPU section, otherwise adding
+     * the per-CPU offset to it leads to unrelated memory. */
+    let symbols = cli.symbols.as_deref().map(load_symbols).transpose()?;
+    let lookup = |sym: &str| sym2addr(cli, symbols.as_ref(), sym, HW_BREAKPOINT_RW);
+    let start = lookup(PERCPU_START)?;
+    let end = lookup(PERCPU_END)?;
+    let check_percpu = |what: &str, addr: usize| {
+        if addr < start || addr >= end {
+            return Err(anyhow!(
+                "{what} at {addr:#x} is not in the per-CPU section [{start:#x}, {end:#x})"
+            ));
+        }
+        Ok(())
     };
 
-    (bp_type, bp_len)
+    match root {
+        Some(KexprRoot::Global(sym)) => {
+            check_percpu(sym, lookup(sym)?)?;
+            let values = percpu_global_kexpr(sym, &arg.expr, &cpus)?;
+            Ok(cpus
+                .into_iter()
+                .zip(values)
+                .map(|(cpu, value)| (Some(cpu), value.addr, value.size))
+                .collect())
+        }
+        Some(root) => Err(anyhow!(
+            "Per-CPU watch {} is not supported on {root}",
+            arg.expr
+        )),
+        None => {
+            let (addr, size) = parse_addr(cli, arg)?;
+            check_percpu(&arg.expr, addr)?;
+            let offsets = per_cpu_offsets(&cpus)?;
+            Ok(cpus
+                .into_iter()
+                .zip(offsets)
+                .map(|(cpu, offset)| (Some(cpu), addr.wrapping_add(offset), size))
+                .collect())
+        }
+    }
 }
 
+fn parse_breakpoints(watches: &[Watch]) -> Result<Vec<Breakpoint>> {
+    let mut bps: Vec<Breakpoint> = Vec::new();
+    for watch in watches {
+        for bp in watch.breakpoints()? {
+            /* The breakpoint is identified by its address in BPF program */
+            if let Some(b) = bps.iter().find(|b| b.addr == bp.addr) {
+                let other = watches.iter().find(|w| w.owns(b)).unwrap_or(watch);
+                return Err(anyhow!(
+                    "{} and {} are watching the same address {:x}",
+                    other.expr,
+                    watch.expr,
+                    bp.addr
+                ));
+            }
+            bps.push(bp);
+        }
+    }
+
+    if bps.len() > MAX_WATCHES {
+        return Err(anyhow!(
+            "At most {MAX_WATCHES} hardware breakpoints are allowed"
+        ));
+    }
+    check_hw_bps(&bps)?;
+
+    Ok(bps)
+}
+
+fn arm_breakpoints(
+    bps: &[Breakpoint],
+    scope: &BpScope,
+    prog: &mut ProgramMut,
+) -> Result<Vec<Link>> {
+    let mut links = Vec::new();
+    for (idx, bp) in bps.iter().enumerate() {
+        /* The breakpoint which is only for a CPU is skipped on the
+         * other CPUs */
+        let pinned;
+        let scope = match (bp.cpu, scope) {
+            (None, scope) => scope,
+            (Some(cpu), BpScope::Cpus(cpus)) if cpus.contains(&cpu) => {
+                pinned = BpScope::Cpus(vec![cpu]);
+                &pinned
+            }
+            (Some(_), BpScope::Cpus(_)) => continue,
+            (Some(cpu), BpScope::Tasks(_)) => {
+                return Err(anyhow!(
+                    "Breakpoint {} for CPU {cpu} can't be armed on tasks",
+                    idx + 1
+                ))
+            }
+        };
+
+        let link = attach_breakpoint(bp.addr, bp.bp_type, bp.bp_len, scope, prog).map_err(|e| {
+            anyhow!(
+                "Failed to arm breakpoint {} of {} for watch {}: {e}",
+                idx + 1,
+                bps.len(),
+                bp.watch
+            )
+        })?;
+        links.extend(link);
+    }
+
+    Ok(links)
+}
+
+/* Describe where the address is from. For the kexpr, it is the
+ * expression on its root. Otherwise, find the symbol which the
+ * address belongs to, or just use the expression if it is unknown. */
+fn annotate_addr(kresolver: &KSymResolver, watch: &Watch, addr: usize) -> String {
+    let offset = addr - watch.addr;
+    let expr = match offset {
+        0 => watch.expr.clone(),
+        _ => format!("{}+{offset:#x}", watch.expr),
+    };
+
+    match (&watch.root, watch.percpu) {
+        (Some(root), Some(cpu)) => return format!("{expr} on {root} of CPU {cpu}"),
+        (Some(root), None) => return format!("{expr} on {root}"),
+        (None, Some(cpu)) => return format!("{expr} of CPU {cpu}"),
+        (None, None) => {}
+    }
+
+    match kresolver.find_addr(addr) {
+        Some((sym, offset)) => sym.describe(offset),
+        None => expr,
+    }
+}
+
+/* Warn if the watch doesn't make sense for the kind of symbol where
+ * it is, which is likely to be a mistake. */
+fn check_sym_kind(kresolver: &KSymResolver, watch: &Watch) {
+    if watch.root.is_some() || watch.percpu.is_some() {
+        return;
+    }
+
+    let Some((sym, offset)) = kresolver.find_addr(watch.addr) else {
+        return;
+    };
+    let desc = sym.describe(offset);
+
+    if watch.bp_type == HW_BREAKPOINT_X && sym.kind != KSYM_FUNC {
+        println!(
+            "Warning: watch {} executes on {desc}, which is {} rather than text",
+            watch.expr,
+            sym.kind_name()
+        );
+    } else if watch.bp_type == HW_BREAKPOINT_W && sym.kind == KSYM_RODATA {
+        println!(
+            "Warning: watch {} writes to {desc}, which is read-only data",
+            watch.expr
+        );
+    }
+}
+
+fn format_list<T: ToString>(list: &[T]) -> String {
+    list.iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/* Print what would be armed for the watchpoints, without loading the
+ * BPF program or opening any perf event. */
+fn dry_run(
+    cli: &Cli,
+    kresolver: &KSymResolver,
+    watches: &[Watch],
+    bps: &[Breakpoint],
+) -> Result<()> {
+    for watch in watches {
+        println!(
+            "Watchpoint {}: {}{} at {:x} ({})",
+            watch.id,
+            bp_kind_name(watch.bp_type),
+            watch.bp_len,
+            watch.addr,
+            annotate_addr(kresolver, watch, watch.addr)
+        );
+
+        for bp in bps.iter().filter(|bp| watch.owns(bp)) {
+            println!(
+                "\tpart {}: {}{} at {:x} ({})",
+                bp.part,
+                bp_kind_name(bp.bp_type),
+                bp.bp_len,
+                bp.addr,
+                annotate_addr(kresolver, watch, bp.addr)
+            );
+        }
+    }
+
+    if cli.child_only {
+        println!("Scope: the command and its children");
+    } else if let Some(BpScope::Tasks(tids)) = cli.task_scope()? {
+        println!("Scope: tasks {}", format_list(&tids));
+    } else {
+        let tracker = CpuTracker::new(cli.cpus())?;
+        println!("Scope: CPUs {}", format_list(tracker.cpus()));
+        let offline = tracker.offline_cpus();
+        if !offline.is_empty() {
+            println!("\tCPUs {} are offline", format_list(&offline));
+        }
+    }
+
+    if !cli.command.is_empty() {
+        println!("Command: {} (not run)", cli.command.join(" "));
+    }
+
+    Ok(())
+}
+
+/* The interval to check whether any CPU is online or offline */
+const HOTPLUG_INTERVAL: Duration = Duration::from_secs(1);
+
+/* The exit status if any watchpoint is hit. Note that 1 is
+ * reserved for the error. */
+const EXIT_HIT: u8 = 2;
+
 static RUNNING: AtomicBool = AtomicBool::new(true);
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+/* The hits are counted by the BPF program on each CPU, so a hit is
+ * counted even if its messages are dropped. */
+fn read_hits(hit_count: &impl MapCore) -> Result<u64> {
+    let key = 0u32.to_ne_bytes();
+    let counts = hit_count
+        .lookup_percpu(&key, MapFlags::ANY)?
+        .unwrap_or_default();
+
+    Ok(counts
+        .iter()
+        .map(|count| u64::from_ne_bytes(count[..8].try_into().unwrap()))
+        .sum())
+}
+
+fn main() -> Result<ExitCode> {
+    let mut cli = Cli::parse();
+    if let Some(path) = cli.config.clone() {
+        let config = Config::from_file(&path)?;
+        cli.apply_config(config);
+        cli.check_conflicts()?;
+    }
 
     if sudo::check() != sudo::RunningAs::Root {
         println!("(kmemsnoop: need to escalate for root permission)");
         sudo::escalate_if_needed().map_err(|e| anyhow!("Failed to escalate to root: {e}"))?;
     }
 
-    let (bp_type, bp_len) = parse_bp(&cli);
-    let addr = parse_addr(&cli, bp_type)?;
+    if let Some(SubCmd::Syms {
+        pattern,
+        regex,
+        vmlinux,
+    }) = &cli.subcmd
+    {
+        list_syms(pattern, *regex, vmlinux.as_deref())?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let watches = parse_watches(&cli)?;
+    let bps = parse_breakpoints(&watches)?;
+
+    let kresolver = KSymResolver::new();
+    for watch in &watches {
+        check_sym_kind(&kresolver, watch);
+    }
+
+    if cli.dry_run {
+        dry_run(&cli, &kresolver, &watches, &bps)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let mut workload = None;
+    if !cli.command.is_empty() {
+        workload = Some(Workload::prepare(&cli.command, cli.user)?);
+    }
 
-    println!("Watchpoint attached on {addr:x}");
+    let task_scope = match &workload {
+        Some(workload) if cli.child_only => Some(BpScope::Tasks(vec![workload.pid()])),
+        _ => cli.task_scope()?,
+    };
 
     /* We may have to bump RLIMIT_MEMLOCK for libbpf explicitly */
     if cfg!(bump_memlock_rlimit_manually) {
@@ -191,9 +942,6 @@ fn main() -> Result<()> {
     /* Open BPF application */
     let open_skel = builder.open(&mut open_object)?;
 
-    open_skel.maps.rodata_data.bp_type = bp_type;
-    open_skel.maps.rodata_data.bp_len = bp_len;
-
     /* Load & verify BPF programs */
     let mut skel = open_skel.load()?;
     let _ = skel.attach()?;
@@ -201,12 +949,65 @@ fn main() -> Result<()> {
     let progs = skel.progs;
     let mut prog = progs.perf_event_handler;
 
+    for bp in &bps {
+        let key = (bp.addr as u64).to_ne_bytes();
+        skel.maps
+            .watch_map
+            .update(&key, &bp.to_map_value(), MapFlags::ANY)?;
+    }
+
     /* The link should be hold to represent the lifetime of
-     * breakpoint. */
-    let _links = attach_breakpoint(addr, bp_type, bp_len, &mut prog)?;
+     * breakpoint. For the breakpoints on CPUs, the links are
+     * kept for each CPU so they can follow the CPU hotplug. */
+    let _task_links = match &task_scope {
+        Some(scope) => arm_breakpoints(&bps, scope, &mut prog)?,
+        None => Vec::new(),
+    };
+
+    let mut cpu_links: BTreeMap<c_int, Vec<Link>> = BTreeMap::new();
+    let mut tracker = match task_scope {
+        Some(_) => None,
+        None => Some(CpuTracker::new(cli.cpus())?),
+    };
+    if let Some(cpus) = &tracker {
+        for &cpu in cpus.cpus() {
+            let links = arm_breakpoints(&bps, &BpScope::Cpus(vec![cpu]), &mut prog)?;
+            cpu_links.insert(cpu, links);
+        }
+
+        for cpu in cpus.offline_cpus() {
+            println!("CPU {cpu} is offline, watch it when it is online");
+        }
+    }
+
+    let mut annotations = HashMap::new();
+    for watch in &watches {
+        println!(
+            "Watchpoint {} attached on {:x} ({})",
+            watch.id,
+            watch.addr,
+            annotate_addr(&kresolver, watch, watch.addr)
+        );
+
+        let parts = bps.iter().filter(|bp| watch.owns(bp));
+        let multipart = parts.clone().count() > 1;
+        for bp in parts {
+            let desc = annotate_addr(&kresolver, watch, bp.addr);
+            if multipart {
+                println!(
+                    "\tpart {}: {} bytes at {:x} ({desc})",
+                    bp.part, bp.bp_len, bp.addr
+                );
+            }
+            annotations.insert(bp.addr as u64, desc);
+        }
+    }
+    set_annotations(annotations);
+    set_format(cli.format.unwrap_or(OutputFormat::Text));
 
     let mut builder = RingBufferBuilder::new();
     let msg_ringbuf = skel.maps.msg_ringbuf;
+    let hit_count = skel.maps.hit_count;
     builder.add(&msg_ringbuf, msg_handler)?;
     let msg = builder.build()?;
 
@@ -214,14 +1015,195 @@ fn main() -> Result<()> {
         RUNNING.store(false, Ordering::SeqCst);
     })?;
 
+    /* Run the command after everything is ready */
+    if let Some(workload) = &mut workload {
+        workload.start()?;
+    }
+
+    let max_hits = cli.max_hits();
+    let mut status = None;
+    let start = Instant::now();
+    let mut last_update = Instant::now();
     while RUNNING.load(Ordering::SeqCst) {
         match msg.poll(Duration::from_millis(100)) {
             Ok(()) => {}
             Err(e) if e.kind() == libbpf_rs::ErrorKind::Interrupted => {}
             Err(e) => return Err(anyhow::Error::msg(e)),
         }
+
+        if let Some(max_hits) = max_hits {
+            if read_hits(&hit_count)? >= max_hits {
+                break;
+            }
+        }
+
+        if let Some(duration) = cli.duration {
+            if start.elapsed() >= duration {
+                break;
+            }
+        }
+
+        if let Some(workload) = &mut workload {
+            status = workload.try_wait()?;
+            if status.is_some() {
+                break;
+            }
+        }
+
+        let Some(cpus) = &mut tracker else {
+            continue;
+        };
+        if last_update.elapsed() < HOTPLUG_INTERVAL {
+            continue;
+        }
+        last_update = Instant::now();
+
+        let (up, down) = cpus.update()?;
+        for cpu in down {
+            println!("CPU {cpu} is offline, release its breakpoints");
+            cpu_links.remove(&cpu);
+        }
+        for cpu in up {
+            match arm_breakpoints(&bps, &BpScope::Cpus(vec![cpu]), &mut prog) {
+                Ok(links) => {
+                    println!("CPU {cpu} is online, arm its breakpoints");
+                    cpu_links.insert(cpu, links);
+                }
+                Err(e) => println!("Failed to arm breakpoints on CPU {cpu}: {e}"),
+            }
+        }
     }
 
-    println!("Terminate kmemsnoop");
-    Ok(())
+    /* Report the events which are left */
+    msg.consume()?;
+
+    let hits = read_hits(&hit_count)?;
+    println!("Terminate kmemsnoop: {hits} hits");
+    let dropped = hits.saturating_sub(REPORTED.load(Ordering::SeqCst));
+    if dropped > 0 {
+        println!("\t{dropped} hits are not reported because the ring buffer is full");
+    }
+
+    /* Pass through the exit status of the command if it is
+     * finished by itself, unless the hits are asked for. */
+    if let Some(status) = status {
+        if !(cli.hit_status && hits > 0) {
+            return Ok(ExitCode::from(status));
+        }
+    }
+
+    if let Some(workload) = &mut workload {
+        workload.terminate();
+    }
+
+    if hits > 0 {
+        return Ok(ExitCode::from(EXIT_HIT));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod cli_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpus() -> Result<()> {
+        let cli = Cli::try_parse_from(["kmemsnoop", "--cpus", "0-3,8", "rw4", "jiffies"])?;
+        assert_eq!(cli.cpus(), Some(vec![0, 1, 2, 3, 8]));
+
+        let cli = Cli::try_parse_from(["kmemsnoop", "rw4", "jiffies"])?;
+        assert_eq!(cli.cpus(), None);
+
+        assert!(Cli::try_parse_from(["kmemsnoop", "--cpus", "3-1", "rw4", "jiffies"]).is_err());
+        assert!(
+            Cli::try_parse_from(["kmemsnoop", "--cpus", "0", "--task", "1", "rw4", "jiffies"])
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_workload() -> Result<()> {
+        let cli = Cli::try_parse_from(["kmemsnoop", "--hit-status", "w4", "jiffies", "--", "ls"])?;
+        assert!(cli.hit_status && !cli.user);
+        assert_eq!(cli.command, vec!["ls"]);
+
+        assert!(Cli::try_parse_from(["kmemsnoop", "--hit-status", "w4", "jiffies"]).is_err());
+        assert!(Cli::try_parse_from(["kmemsnoop", "--user", "w4", "jiffies"]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_config() -> Result<()> {
+        let mut cli = Cli::try_parse_from(["kmemsnoop", "--format", "text"])?;
+        cli.apply_config(Config::parse("format = \"json\"\ncount = 3\n")?);
+        assert!(cli.check_conflicts().is_ok());
+        assert!(cli.format == Some(OutputFormat::Text));
+        assert_eq!(cli.count, Some(3));
+
+        let mut cli = Cli::try_parse_from(["kmemsnoop", "--task", "1"])?;
+        cli.apply_config(Config::parse("percpu = \"all\"\n")?);
+        let err = cli.check_conflicts().unwrap_err();
+        assert_eq!(err.to_string(), "percpu can't be specified with task");
+
+        let mut cli = Cli::try_parse_from(["kmemsnoop", "--exit-on-hit"])?;
+        cli.apply_config(Config::parse(
+            "[[watch]]\nkind = \"rw\"\nexpr = \"a\"\npercpu = \"all\"\n",
+        )?);
+        assert!(cli.check_conflicts().is_ok());
+
+        let mut cli = Cli::try_parse_from(["kmemsnoop", "--tgid", "1"])?;
+        cli.apply_config(Config::parse(
+            "[[watch]]\nkind = \"rw\"\nexpr = \"a\"\npercpu = \"all\"\n",
+        )?);
+        assert!(cli.check_conflicts().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_kexpr_root() -> Result<()> {
+        let cli = Cli::try_parse_from(["kmemsnoop", "--pid-task", "1", "rw4", "&on_rq"])?;
+        assert!(matches!(cli.kexpr_root(), Some(KexprRoot::Task(1))));
+
+        let cli = Cli::try_parse_from([
+            "kmemsnoop",
+            "--net-dev",
+            "eth0",
+            "--netns-pid",
+            "1",
+            "rw4",
+            "&mtu",
+        ])?;
+        assert!(matches!(cli.kexpr_root(), Some(KexprRoot::NetDev { .. })));
+
+        assert!(Cli::try_parse_from([
+            "kmemsnoop",
+            "--pid-task",
+            "1",
+            "--global",
+            "init_net",
+            "rw4",
+            "&on_rq"
+        ])
+        .is_err());
+        assert!(Cli::try_parse_from([
+            "kmemsnoop",
+            "--irq",
+            "16",
+            "--module",
+            "e1000e",
+            "rw4",
+            "&refcnt"
+        ])
+        .is_err());
+        assert!(
+            Cli::try_parse_from(["kmemsnoop", "--sb", "/", "--inode", "/", "rw8", "&s_flags"])
+                .is_err()
+        );
+
+        Ok(())
+    }
 }
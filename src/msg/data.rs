@@ -1,6 +1,18 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::msg::{json_str, OutputFormat};
 use crate::utils::cast;
 use plain::Plain;
 
+/* The description of the address for each breakpoint, which is
+ * printed along with the data. */
+static ANNOTATIONS: OnceLock<HashMap<u64, String>> = OnceLock::new();
+
+pub fn set_annotations(annotations: HashMap<u64, String>) {
+    let _ = ANNOTATIONS.set(annotations);
+}
+
 #[repr(C)]
 struct DataMsg {
     addr: u64,
@@ -8,12 +20,27 @@ struct DataMsg {
 }
 unsafe impl Plain for DataMsg {}
 
-pub fn data_msg_handler(bytes: &[u8]) -> i32 {
+/* Report the data in the text format, or return it as a JSON object
+ * for the JSON format. */
+pub fn data_msg_handler(bytes: &[u8], format: OutputFormat) -> Option<String> {
     let msg: &DataMsg = cast(bytes);
     let addr = msg.addr;
     let val = msg.val;
+    let desc = ANNOTATIONS.get().and_then(|a| a.get(&addr));
+
+    if format == OutputFormat::Json {
+        let desc = desc.map_or(String::new(), |desc| {
+            format!(",\"desc\":{}", json_str(desc))
+        });
+        return Some(format!(
+            "{{\"addr\":\"{addr:#x}\",\"value\":\"{val:#x}\"{desc}}}"
+        ));
+    }
 
-    println!("\tdata@0x{addr:x} = {val:x}");
+    match desc {
+        Some(desc) => println!("\tdata@0x{addr:x} ({desc}) = {val:x}"),
+        None => println!("\tdata@0x{addr:x} = {val:x}"),
+    }
 
-    0
+    None
 }
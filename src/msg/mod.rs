@@ -2,23 +2,73 @@ mod data;
 mod stack;
 
 use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
 
 use crate::msg::data::data_msg_handler;
 use crate::msg::stack::stack_msg_handler;
 use crate::utils::cast;
 
+use anyhow::{anyhow, Result};
 use plain::Plain;
 
+pub use crate::msg::data::set_annotations;
+
 const MSG_TYPE_STACK: u64 = 0;
 const MSG_TYPE_DATA: u64 = 1;
 const TASK_COMM_LEN: usize = 16;
 
+/* How the events are printed */
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    /* One JSON object per line for each message */
+    Json,
+}
+
+pub fn parse_format(s: &str) -> Result<OutputFormat> {
+    match s {
+        "text" => Ok(OutputFormat::Text),
+        "json" => Ok(OutputFormat::Json),
+        _ => Err(anyhow!("Invalid format {s}, expect text or json")),
+    }
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+pub fn set_format(format: OutputFormat) {
+    let _ = FORMAT.set(format);
+}
+
+/* Quote the string for JSON */
+pub(super) fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/* The number of hits which are reported. Every hit reports exactly
+ * one stack message, unless it is dropped by the BPF program. */
+pub static REPORTED: AtomicU64 = AtomicU64::new(0);
+
 #[repr(C)]
 struct MsgEnt {
     id: u64,
     typ: u64,
     timestamp: u64,
     pid: u64,
+    watch: u64,
+    part: u64,
     cmd: [u8; TASK_COMM_LEN],
 }
 unsafe impl Plain for MsgEnt {}
@@ -54,18 +104,54 @@ pub fn msg_handler(bytes: &[u8]) -> i32 {
     let ent: &MsgEnt = cast(ent);
     let id = ent.id;
     let pid = ent.pid;
+    let watch = ent.watch;
+    let part = ent.part;
 
     let timestamp = ent.timestamp;
     let t1 = timestamp / 1000_000_000;
     let t2 = timestamp % 1000_000_000;
-    println!(
-        "[{t1}.{t2:09}] id={id} pid={pid} ({}):",
-        &format_cmd(&ent.cmd)
-    );
-
-    match ent.typ {
-        MSG_TYPE_STACK => stack_msg_handler(inner),
-        MSG_TYPE_DATA => data_msg_handler(inner),
+
+    let format = FORMAT.get().copied().unwrap_or(OutputFormat::Text);
+    if format == OutputFormat::Text {
+        println!(
+            "[{t1}.{t2:09}] id={id} watch={watch}.{part} pid={pid} ({}):",
+            &format_cmd(&ent.cmd)
+        );
+    }
+
+    let (key, val) = match ent.typ {
+        MSG_TYPE_STACK => {
+            REPORTED.fetch_add(1, Ordering::SeqCst);
+            ("stack", stack_msg_handler(inner, format))
+        }
+        MSG_TYPE_DATA => ("data", data_msg_handler(inner, format)),
         _ => panic!("Invalid message with wrong type"),
+    };
+
+    if let Some(val) = val {
+        let len = ent
+            .cmd
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(ent.cmd.len());
+        let cmd = String::from_utf8_lossy(&ent.cmd[..len]);
+        println!(
+            "{{\"id\":{id},\"time\":\"{t1}.{t2:09}\",\"watch\":{watch},\"part\":{part},\"pid\":{pid},\"cmd\":{},\"{key}\":{val}}}",
+            json_str(&cmd)
+        );
+    }
+
+    0
+}
+
+#[cfg(test)]
+mod msg_tests {
+    use super::*;
+
+    #[test]
+    fn test_json_str() {
+        assert_eq!(json_str("kworker/0:1"), "\"kworker/0:1\"");
+        assert_eq!(json_str("a\"b\\c\n"), "\"a\\\"b\\\\c\\n\"");
+        assert_eq!(json_str("\u{1}"), "\"\\u0001\"");
     }
 }
@@ -1,3 +1,4 @@
+use crate::msg::{json_str, OutputFormat};
 use crate::utils::cast;
 
 use std::mem::size_of;
@@ -20,17 +21,21 @@ unsafe impl Plain for StackMsg {}
 
 const ADDR_WIDTH: usize = 16;
 
-fn print_frame(name: &str, addr_info: Option<(Addr, Addr, usize)>, code_info: &Option<CodeInfo>) {
-    let code_info = code_info.as_ref().map(|code_info| {
+fn format_code_info(code_info: &Option<CodeInfo>) -> Option<String> {
+    code_info.as_ref().map(|code_info| {
         let path = code_info.to_path();
         let path = path.display();
 
         match (code_info.line, code_info.column) {
-            (Some(line), Some(col)) => format!(" {path}:{line}:{col}"),
-            (Some(line), None) => format!(" {path}:{line}"),
-            (None, _) => format!(" {path}"),
+            (Some(line), Some(col)) => format!("{path}:{line}:{col}"),
+            (Some(line), None) => format!("{path}:{line}"),
+            (None, _) => format!("{path}"),
         }
-    });
+    })
+}
+
+fn print_frame(name: &str, addr_info: Option<(Addr, Addr, usize)>, code_info: &Option<CodeInfo>) {
+    let code_info = format_code_info(code_info).map(|info| format!(" {info}"));
 
     if let Some((input_addr, addr, offset)) = addr_info {
         println!(
@@ -51,13 +56,40 @@ fn print_frame(name: &str, addr_info: Option<(Addr, Addr, usize)>, code_info: &O
     }
 }
 
-pub fn stack_msg_handler(bytes: &[u8]) -> i32 {
+/* The frame in JSON, the address is None for the inlined function */
+fn json_frame(
+    name: &str,
+    addr_info: Option<(Addr, Addr, usize)>,
+    code_info: &Option<CodeInfo>,
+) -> String {
+    let mut fields = Vec::new();
+    if let Some((input_addr, _, offset)) = addr_info {
+        fields.push(format!("\"addr\":\"{input_addr:#x}\""));
+        fields.push(format!("\"sym\":{}", json_str(name)));
+        fields.push(format!("\"offset\":{offset}"));
+    } else {
+        fields.push(format!("\"sym\":{}", json_str(name)));
+        fields.push("\"inlined\":true".to_string());
+    }
+    if let Some(info) = format_code_info(code_info) {
+        fields.push(format!("\"src\":{}", json_str(&info)));
+    }
+
+    format!("{{{}}}", fields.join(","))
+}
+
+/* Report the stack in the text format, or return the frames as a
+ * JSON array for the JSON format. */
+pub fn stack_msg_handler(bytes: &[u8], format: OutputFormat) -> Option<String> {
     let msg: &StackMsg = cast(bytes);
     /* bpf_get_stack() reports a negative errno on failure. */
     let kstack_sz = msg.kstack_sz as i64;
     if kstack_sz < 0 {
+        if format == OutputFormat::Json {
+            return Some(format!("{{\"errno\":{}}}", -kstack_sz));
+        }
         println!("\tfailed to get stack: errno {}", -kstack_sz);
-        return 0;
+        return None;
     }
     let stack_sz = (kstack_sz as usize / size_of::<u64>()).min(msg.kstack.len());
     let addrs = &msg.kstack[..stack_sz];
@@ -66,6 +98,7 @@ pub fn stack_msg_handler(bytes: &[u8]) -> i32 {
     let symbolizer = Symbolizer::new();
     let syms = symbolizer.symbolize(&src, Input::AbsAddr(addrs)).unwrap();
 
+    let mut frames = Vec::new();
     for (input_addr, sym) in addrs.iter().copied().zip(syms) {
         match sym {
             Symbolized::Sym(Sym {
@@ -76,16 +109,32 @@ pub fn stack_msg_handler(bytes: &[u8]) -> i32 {
                 inlined,
                 ..
             }) => {
-                print_frame(&name, Some((input_addr, addr, offset)), &code_info);
+                let addr_info = Some((input_addr, addr, offset));
+                if format == OutputFormat::Json {
+                    frames.push(json_frame(&name, addr_info, &code_info));
+                    for frame in inlined.iter() {
+                        frames.push(json_frame(&frame.name, None, &frame.code_info));
+                    }
+                    continue;
+                }
+
+                print_frame(&name, addr_info, &code_info);
                 for frame in inlined.iter() {
                     print_frame(&frame.name, None, &frame.code_info);
                 }
             }
             Symbolized::Unknown(..) => {
+                if format == OutputFormat::Json {
+                    frames.push(format!("{{\"addr\":\"{input_addr:#x}\"}}"));
+                    continue;
+                }
                 println!("{input_addr:#0width$x}: <no-symbol>", width = ADDR_WIDTH)
             }
         }
     }
 
-    0
+    match format {
+        OutputFormat::Json => Some(format!("[{}]", frames.join(","))),
+        OutputFormat::Text => None,
+    }
 }
@@ -29,20 +29,35 @@ fn attach_perf_event(
     };
 
     if efd < 0 {
-        return Err(anyhow!(format!(
-            "perf_event_open() fail: {}",
-            Error::last_os_error()
-        )));
+        let err = Error::last_os_error();
+        /* The kernel reports ENOSPC when all the debug registers
+         * are occupied. */
+        if err.raw_os_error() == Some(libc::ENOSPC) {
+            return Err(anyhow!(
+                "perf_event_open() fail: not enough debug registers for the breakpoint"
+            ));
+        }
+
+        return Err(anyhow!(format!("perf_event_open() fail: {err}")));
     }
 
     let link = prog.attach_perf_event(efd)?;
     Ok(link)
 }
 
+/* Which tasks can trigger the breakpoint */
+pub enum BpScope {
+    /* Every task running on the given CPUs */
+    Cpus(Vec<c_int>),
+    /* Only the given tasks and their children */
+    Tasks(Vec<pid_t>),
+}
+
 pub fn attach_breakpoint(
     symbol_addr: usize,
     bp_type: u32,
     bp_len: u64,
+    scope: &BpScope,
     prog: &mut ProgramMut,
 ) -> Result<Vec<Link>> {
     let mut attr = perf_event_attr::default();
@@ -74,9 +89,22 @@ pub fn attach_breakpoint(
     }
 
     let mut links = Vec::new();
-    for cpu in get_online_cpus() {
-        let link = attach_perf_event(&mut attr, -1, cpu, -1, prog)?;
-        links.push(link);
+    match scope {
+        BpScope::Cpus(cpus) => {
+            for &cpu in cpus {
+                let link = attach_perf_event(&mut attr, -1, cpu, -1, prog)?;
+                links.push(link);
+            }
+        }
+        BpScope::Tasks(tids) => {
+            /* The breakpoint is also installed on the children which
+             * are created after this. */
+            attr.set_inherit(1);
+            for &tid in tids {
+                let link = attach_perf_event(&mut attr, tid, -1, -1, prog)?;
+                links.push(link);
+            }
+        }
     }
 
     Ok(links)
@@ -0,0 +1,259 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+
+/* The address expression which is made of kernel symbols and numbers
+ * with +, -, * and parentheses, e.g. "some_array+0x40*3". The grammar:
+ *
+ *   expr   := term (('+' | '-') term)*
+ *   term   := factor ('*' factor)*
+ *   factor := NUMBER | SYMBOL | '(' expr ')'
+ *
+ * A NUMBER is hex with "0x" prefix, or decimal without it. A word which
+ * starts with a letter is always a SYMBOL, so a typo of the symbol is
+ * never taken as an address. A SYMBOL can be prefixed by the module
+ * name, e.g. "modname:sym". */
+#[derive(PartialEq)]
+enum Token {
+    Num(usize),
+    Sym(String),
+    Plus,
+    Minus,
+    Mul,
+    LParen,
+    RParen,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Num(num) => write!(f, "{num:#x}"),
+            Token::Sym(sym) => write!(f, "{sym}"),
+            Token::Plus => write!(f, "'+'"),
+            Token::Minus => write!(f, "'-'"),
+            Token::Mul => write!(f, "'*'"),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+        }
+    }
+}
+
+fn is_sym_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$' || c == ':'
+}
+
+fn tokenize(expr: &str) -> Result<Vec<(usize, Token)>> {
+    let chars = expr.char_indices().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        let (pos, c) = chars[idx];
+        let col = pos + 1;
+        idx += 1;
+
+        let token = match c {
+            ' ' | '\t' => continue,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' => Token::Mul,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            _ if is_sym_char(c) => {
+                while idx < chars.len() && is_sym_char(chars[idx].1) {
+                    idx += 1;
+                }
+                let end = chars.get(idx).map_or(expr.len(), |&(pos, _)| pos);
+                let word = &expr[pos..end];
+
+                if c.is_ascii_digit() {
+                    let num = match word.strip_prefix("0x") {
+                        Some(hex) => usize::from_str_radix(hex, 16),
+                        None => word.parse::<usize>(),
+                    }
+                    .map_err(|e| anyhow!("Invalid number {word} at column {col}: {e}"))?;
+                    Token::Num(num)
+                } else {
+                    Token::Sym(word.to_string())
+                }
+            }
+            _ => return Err(anyhow!("Unexpected '{c}' at column {col}")),
+        };
+
+        tokens.push((col, token));
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end_col: usize,
+    lookup: &'a dyn Fn(&str) -> Result<usize>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn col(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.end_col, |&(col, _)| col)
+    }
+
+    fn expr(&mut self) -> Result<usize> {
+        let mut value = self.term()?;
+
+        loop {
+            let col = self.col();
+            let overflow = || anyhow!("Overflow at column {col}");
+            match self.peek() {
+                Some(Token::Plus) => {
+                    self.pos += 1;
+                    value = value.checked_add(self.term()?).ok_or_else(overflow)?;
+                }
+                Some(Token::Minus) => {
+                    self.pos += 1;
+                    value = value.checked_sub(self.term()?).ok_or_else(overflow)?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<usize> {
+        let mut value = self.factor()?;
+
+        while let Some(Token::Mul) = self.peek() {
+            let col = self.col();
+            self.pos += 1;
+            value = value
+                .checked_mul(self.factor()?)
+                .ok_or(anyhow!("Overflow at column {col}"))?;
+        }
+
+        Ok(value)
+    }
+
+    fn factor(&mut self) -> Result<usize> {
+        let col = self.col();
+        let Some((_, token)) = self.tokens.get(self.pos) else {
+            return Err(anyhow!("Unexpected end of expression at column {col}"));
+        };
+        self.pos += 1;
+
+        match token {
+            Token::Num(num) => Ok(*num),
+            Token::Sym(sym) => self.symbol(sym, col),
+            Token::LParen => {
+                let value = self.expr()?;
+                if self.peek() != Some(&Token::RParen) {
+                    return Err(anyhow!("Expect ')' at column {}", self.col()));
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            _ => Err(anyhow!("Unexpected {token} at column {col}")),
+        }
+    }
+
+    fn symbol(&self, sym: &str, col: usize) -> Result<usize> {
+        (self.lookup)(sym).map_err(|e| {
+            /* The hex address needs the "0x" prefix now, so tell it
+             * if the word looks like the one without prefix. */
+            if sym.chars().all(|c| c.is_ascii_hexdigit()) {
+                anyhow!("{e} (at column {col}), use 0x{sym} for the address")
+            } else {
+                anyhow!("{e} (at column {col})")
+            }
+        })
+    }
+}
+
+/* Whether the expression is exactly one symbol */
+pub fn is_symbol(expr: &str) -> bool {
+    let expr = expr.trim();
+    match expr.chars().next() {
+        Some(c) if !c.is_ascii_digit() => expr.chars().all(is_sym_char),
+        _ => false,
+    }
+}
+
+/* Evaluate the address expression, where the symbols are resolved to
+ * the address by lookup. */
+pub fn eval_symexpr(expr: &str, lookup: &dyn Fn(&str) -> Result<usize>) -> Result<usize> {
+    let mut parser = Parser {
+        tokens: tokenize(expr)?,
+        pos: 0,
+        end_col: expr.len() + 1,
+        lookup,
+    };
+
+    let value = parser.expr()?;
+    if parser.pos != parser.tokens.len() {
+        return Err(anyhow!("Unexpected token at column {}", parser.col()));
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod symexpr_tests {
+    use super::*;
+
+    fn lookup(sym: &str) -> Result<usize> {
+        match sym {
+            "jiffies_64" => Ok(0x1000),
+            "some_array" => Ok(0x2000),
+            "modname:sym" => Ok(0x3000),
+            _ => Err(anyhow!("Failed to get address of symbol {sym}")),
+        }
+    }
+
+    #[test]
+    fn test_eval_symexpr() -> Result<()> {
+        assert_eq!(eval_symexpr("jiffies_64+4", &lookup)?, 0x1004);
+        assert_eq!(eval_symexpr("some_array+0x40*3", &lookup)?, 0x20c0);
+        assert_eq!(eval_symexpr("modname:sym+8", &lookup)?, 0x3008);
+        assert_eq!(eval_symexpr("(some_array - 0x10) * 2", &lookup)?, 0x3fe0);
+        assert_eq!(eval_symexpr("0xffff0000+0x10", &lookup)?, 0xffff0010);
+        assert_eq!(
+            eval_symexpr("0xffffffff81000000", &lookup)?,
+            0xffffffff81000000
+        );
+
+        /* The numbers without "0x" prefix are decimal */
+        assert_eq!(eval_symexpr("some_array+24", &lookup)?, 0x2018);
+        assert_eq!(eval_symexpr("some_array+8*3", &lookup)?, 0x2018);
+        assert_eq!(eval_symexpr("4096", &lookup)?, 0x1000);
+        assert!(eval_symexpr("10ff", &lookup).is_err());
+
+        /* The word is never taken as the hex address */
+        let err = eval_symexpr("add+4", &lookup).unwrap_err().to_string();
+        assert!(err.ends_with("use 0xadd for the address"), "{err}");
+        let err = eval_symexpr("ffffffff81000000", &lookup)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("0xffffffff81000000"), "{err}");
+
+        assert!(eval_symexpr("jiffies_64+", &lookup).is_err());
+        assert!(eval_symexpr("(jiffies_64", &lookup).is_err());
+        assert!(eval_symexpr("unknown_sym+4", &lookup).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_symbol() -> Result<()> {
+        assert!(is_symbol("jiffies_64"));
+        assert!(is_symbol(" modname:sym "));
+        assert!(!is_symbol("some_array+4"));
+        assert!(!is_symbol("0x1000"));
+        assert!(!is_symbol(""));
+
+        Ok(())
+    }
+}
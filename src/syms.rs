@@ -0,0 +1,107 @@
+use crate::ksym::{
+    relocate_addr, KSymResolver, Ksym, KASLR_ANCHOR, KSYM_FUNC, KSYM_RODATA, VMLINUX_MODULE,
+};
+use crate::watch::suggest_bp_type;
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+use blazesym::inspect;
+use blazesym::inspect::Inspector;
+
+use perf_event_open_sys::bindings::{HW_BREAKPOINT_R, HW_BREAKPOINT_RW, HW_BREAKPOINT_X};
+
+/* Convert the glob pattern to the regex, where '*' matches any
+ * string and '?' matches any character. */
+fn glob2regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/* Get the size of symbols from vmlinux. The size is None if the
+ * symbol can't be found exactly. */
+fn vmlinux_sizes(
+    kresolver: &KSymResolver,
+    syms: &[&Ksym],
+    vmlinux: &str,
+) -> Result<Vec<Option<usize>>> {
+    let src = inspect::Source::Elf(inspect::Elf::new(vmlinux));
+    let inspector = Inspector::new();
+    let mut names = syms.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+    names.push(KASLR_ANCHOR);
+    let mut results = inspector.lookup(&src, &names)?;
+
+    let anchor = results.pop().unwrap_or_default();
+    let anchor = anchor
+        .first()
+        .ok_or(anyhow!("Failed to find {KASLR_ANCHOR} in {vmlinux}"))?;
+    let offset = kresolver.kaslr_offset(anchor.addr as usize)?;
+
+    let sizes = syms
+        .iter()
+        .zip(results)
+        .map(|(sym, infos)| {
+            /* The symbols in modules are not in vmlinux */
+            if sym.module.is_some() {
+                return None;
+            }
+
+            let is_sym = |addr: u64| relocate_addr(addr as usize, offset) == sym.addr;
+            match infos.iter().find(|info| is_sym(info.addr)) {
+                Some(info) => info.size,
+                None if infos.len() == 1 => infos[0].size,
+                None => None,
+            }
+        })
+        .collect();
+
+    Ok(sizes)
+}
+
+pub fn list_syms(pattern: &str, regex: bool, vmlinux: Option<&str>) -> Result<()> {
+    let re = if regex {
+        pattern.to_string()
+    } else {
+        glob2regex(pattern)
+    };
+    let re = Regex::new(&re).map_err(|e| anyhow!("Invalid pattern {pattern}: {e}"))?;
+
+    let kresolver = KSymResolver::new();
+    let syms = kresolver.search(|name| re.is_match(name));
+
+    let sizes = match vmlinux {
+        Some(vmlinux) => vmlinux_sizes(&kresolver, &syms, vmlinux)?,
+        None => vec![None; syms.len()],
+    };
+
+    println!(
+        "{:<18} {:<6} {:<8} {:<8} SYMBOL",
+        "ADDRESS", "KIND", "SIZE", "BP"
+    );
+    for (sym, size) in syms.iter().zip(sizes) {
+        let kind = sym.kind_name();
+        let bp_type = match sym.kind {
+            KSYM_FUNC => HW_BREAKPOINT_X,
+            KSYM_RODATA => HW_BREAKPOINT_R,
+            _ => HW_BREAKPOINT_RW,
+        };
+        let bp = suggest_bp_type(bp_type, sym.addr, size.map(|sz| sz as u64));
+        let size = size.map_or("-".to_string(), |sz| sz.to_string());
+        let module = sym.module.as_deref().unwrap_or(VMLINUX_MODULE);
+
+        println!(
+            "{:<18x} {kind:<6} {size:<8} {bp:<8} {module}:{}",
+            sym.addr, sym.name
+        );
+    }
+
+    Ok(())
+}
@@ -1,7 +1,8 @@
-use libc::{c_char, c_int};
+use libc::{c_char, c_int, pid_t};
 
 use std::ffi::CStr;
 use std::fs;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 
@@ -15,27 +16,98 @@ pub fn hexstr2int(hex: &str) -> Result<usize> {
     Ok(usize::from_str_radix(hex.trim_start_matches("0x"), 16)?)
 }
 
-pub fn get_online_cpus() -> Vec<c_int> {
-    let path = "/sys/devices/system/cpu/online";
-
-    let list = fs::read_to_string(path).expect("Fail to read cpu/online sysfs node");
-
-    let list = list.trim().split(",");
-
+/* Parse the CPU list in "0-3,8" format */
+pub fn parse_cpu_list(list: &str) -> Result<Vec<c_int>> {
     let mut cpus = Vec::new();
-    for range in list {
-        if let Some(pos) = range.find('-') {
-            let start = range[0..pos].parse::<c_int>().unwrap();
-            let end = range[pos + 1..].parse::<c_int>().unwrap();
-            for c in start..=end {
-                cpus.push(c);
+    for range in list.trim().split(",") {
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<c_int>()
+                .map_err(|e| anyhow!("Invalid CPU {s} in list {list}: {e}"))
+        };
+
+        if let Some((start, end)) = range.split_once('-') {
+            let start = parse(start)?;
+            let end = parse(end)?;
+            if start > end {
+                return Err(anyhow!("Invalid CPU range {range} in list {list}"));
             }
+            cpus.extend(start..=end);
         } else {
-            cpus.push(range.parse::<c_int>().unwrap());
+            cpus.push(parse(range)?);
+        }
+    }
+
+    cpus.sort();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/* The CPU list from the command line. It is wrapped, so clap takes the
+ * whole list as one value instead of a list of values. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuList(pub Vec<c_int>);
+
+impl CpuList {
+    pub fn parse(list: &str) -> Result<Self> {
+        Ok(CpuList(parse_cpu_list(list)?))
+    }
+}
+
+fn read_cpu_list(path: &str) -> Result<Vec<c_int>> {
+    let list = fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {path}: {e}"))?;
+
+    parse_cpu_list(&list)
+}
+
+pub fn get_online_cpus() -> Result<Vec<c_int>> {
+    read_cpu_list("/sys/devices/system/cpu/online")
+}
+
+pub fn get_possible_cpus() -> Result<Vec<c_int>> {
+    read_cpu_list("/sys/devices/system/cpu/possible")
+}
+
+/* Parse the duration in "<NUM>[ms|s|m|h]" format, e.g. "30s". The
+ * unit is second if it is omitted. */
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let pos = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(pos);
+    let num = num
+        .parse::<u64>()
+        .map_err(|e| anyhow!("Invalid duration {s}: {e}"))?;
+
+    let secs = |scale: u64| {
+        num.checked_mul(scale)
+            .map(Duration::from_secs)
+            .ok_or(anyhow!("Duration {s} is too long"))
+    };
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(num)),
+        "" | "s" => Ok(Duration::from_secs(num)),
+        "m" => secs(60),
+        "h" => secs(60 * 60),
+        _ => Err(anyhow!(
+            "Invalid unit of duration {s}, expect ms, s, m or h"
+        )),
+    }
+}
+
+/* Get the tids of all the threads in the process */
+pub fn get_threads(pid: pid_t) -> Result<Vec<pid_t>> {
+    let path = format!("/proc/{pid}/task");
+    let entries = fs::read_dir(&path).map_err(|e| anyhow!("Failed to read {path}: {e}"))?;
+
+    let mut tids = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name();
+        if let Some(tid) = name.to_str().and_then(|s| s.parse::<pid_t>().ok()) {
+            tids.push(tid);
         }
     }
 
-    cpus
+    Ok(tids)
 }
 
 #[inline]
@@ -62,3 +134,22 @@ pub fn uname_version() -> Result<(u32, u32)> {
 
     Ok((main, sub))
 }
+
+#[cfg(test)]
+mod utils_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() -> Result<()> {
+        assert_eq!(parse_duration("500ms")?, Duration::from_millis(500));
+        assert_eq!(parse_duration("30")?, Duration::from_secs(30));
+        assert_eq!(parse_duration("2m")?, Duration::from_secs(120));
+        assert_eq!(parse_duration("1h")?, Duration::from_secs(3600));
+
+        assert!(parse_duration("3d").is_err());
+        assert!(parse_duration(&format!("{}m", u64::MAX)).is_err());
+        assert!(parse_duration(&format!("{}h", u64::MAX / 60)).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,548 @@
+use crate::kexpr::KexprRoot;
+
+use anyhow::{anyhow, Result};
+use libc::c_int;
+
+use perf_event_open_sys::bindings::{
+    HW_BREAKPOINT_R, HW_BREAKPOINT_RW, HW_BREAKPOINT_W, HW_BREAKPOINT_X,
+};
+
+/* This should be the same as MAX_WATCHES in bpf/watch.h */
+pub const MAX_WATCHES: usize = 1024;
+
+/* Convert the kind of watchpoint without the length, e.g. "rw" */
+pub fn parse_bp_kind(kind: &str) -> Option<u32> {
+    match kind.to_lowercase().as_str() {
+        "r" => Some(HW_BREAKPOINT_R),
+        "w" => Some(HW_BREAKPOINT_W),
+        "rw" => Some(HW_BREAKPOINT_RW),
+        "x" => Some(HW_BREAKPOINT_X),
+        _ => None,
+    }
+}
+
+/* The length of watchpoint in bytes, or "auto" to take the size of
+ * the object which is watched. */
+#[derive(Clone, Copy, PartialEq)]
+pub enum BpLen {
+    Auto,
+    Bytes(u64),
+}
+
+pub fn parse_bp_len(s: &str) -> Result<BpLen> {
+    if s == "auto" {
+        return Ok(BpLen::Auto);
+    }
+
+    let len = s
+        .parse::<u64>()
+        .map_err(|e| anyhow!("Invalid length {s}: {e}"))?;
+    if len == 0 {
+        return Err(anyhow!("Invalid length {s}"));
+    }
+
+    Ok(BpLen::Bytes(len))
+}
+
+/* The type of watchpoint in "<KIND><LEN>" format, e.g. "rw4". The
+ * length can be any number of bytes. If it is omitted(e.g. "rw"), the
+ * length is given elsewhere or from the size of the object. */
+#[derive(Clone)]
+pub struct BpType {
+    pub kind: u32,
+    pub len: BpLen,
+}
+
+pub fn parse_bp_type(s: &str) -> Result<BpType> {
+    let pos = s.find(|c: char| c.is_ascii_digit()).unwrap_or(s.len());
+    let (kind, len) = s.split_at(pos);
+
+    let kind = parse_bp_kind(kind).ok_or(anyhow!(
+        "Invalid watchpoint type {s}, expect r, w, rw or x with the length"
+    ))?;
+
+    let len = if len.is_empty() {
+        BpLen::Auto
+    } else {
+        parse_bp_len(len).map_err(|e| anyhow!("Invalid watchpoint type {s}: {e}"))?
+    };
+
+    Ok(BpType { kind, len })
+}
+
+/* Which instance of the per-CPU variable to watch */
+#[derive(Clone, Copy)]
+pub enum PerCpu {
+    Cpu(c_int),
+    /* The instance of every CPU, which is only watched on its CPU */
+    All,
+}
+
+pub fn parse_percpu(s: &str) -> Result<PerCpu> {
+    if s == "all" {
+        return Ok(PerCpu::All);
+    }
+
+    let cpu = s
+        .parse::<c_int>()
+        .map_err(|e| anyhow!("Invalid CPU {s}, expect the number or 'all': {e}"))?;
+    if cpu < 0 {
+        return Err(anyhow!("Invalid CPU {s}, expect the number or 'all'"));
+    }
+    Ok(PerCpu::Cpu(cpu))
+}
+
+/* The watchpoint which is requested from the command line or the
+ * config file, before its expression is resolved to the address. */
+#[derive(Clone)]
+pub struct WatchArg {
+    pub bp_type: u32,
+    pub bp_len: BpLen,
+    pub expr: String,
+    /* The kexpr root for this watch only. Use the one from the
+     * command line if it is not specified. */
+    pub root: Option<KexprRoot>,
+    /* Whether the expression is a per-CPU variable. Use the one from
+     * the command line if it is not specified. */
+    pub percpu: Option<PerCpu>,
+}
+
+/* Parse the argument in "<BP>:<EXPR>" format */
+pub fn parse_watch_arg(s: &str) -> Result<WatchArg> {
+    let (bp, expr) = s
+        .split_once(':')
+        .ok_or(anyhow!("Invalid watch {s}, expect <BP>:<EXPR>"))?;
+
+    let bp = parse_bp_type(bp)?;
+    if expr.is_empty() {
+        return Err(anyhow!("Empty expression for watch {s}"));
+    }
+
+    Ok(WatchArg {
+        bp_type: bp.kind,
+        bp_len: bp.len,
+        expr: expr.to_string(),
+        root: None,
+        percpu: None,
+    })
+}
+
+/* The alignment rules of the hardware breakpoint on arm64: A data
+ * watchpoint can watch any bytes inside an aligned doubleword, and an
+ * execution breakpoint should be a 4 bytes aligned instruction. */
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    pub const MAX_BP_LEN: u64 = 8;
+    pub const X_BP_LEN: u64 = 4;
+
+    use perf_event_open_sys::bindings::HW_BREAKPOINT_X;
+
+    /* Every kind of breakpoint is supported */
+    pub fn legal_kind(bp_type: u32) -> u32 {
+        bp_type
+    }
+
+    /* The execution breakpoints and the data watchpoints take the
+     * different registers. The number of them depends on the CPU,
+     * which is at least 4 for most of the implementations. */
+    pub fn hw_bp_regs(bp_type: u32) -> (&'static str, usize) {
+        match bp_type {
+            HW_BREAKPOINT_X => ("breakpoint registers", 4),
+            _ => ("watchpoint registers", 4),
+        }
+    }
+
+    pub fn is_legal_x(addr: usize, len: u64) -> bool {
+        len == X_BP_LEN && addr % X_BP_LEN as usize == 0
+    }
+
+    /* The address of the instruction should be aligned */
+    pub fn legal_x_addr(addr: usize) -> usize {
+        addr - addr % X_BP_LEN as usize
+    }
+
+    pub fn is_legal_data(addr: usize, len: u64) -> bool {
+        let offset = (addr % MAX_BP_LEN as usize) as u64;
+        len >= 1 && offset + len <= MAX_BP_LEN
+    }
+
+    pub fn next_bp_len(pos: usize, end: usize) -> u64 {
+        let offset = pos % MAX_BP_LEN as usize;
+        (MAX_BP_LEN as usize - offset).min(end - pos) as u64
+    }
+}
+
+/* The alignment rules of the hardware breakpoint on x86: A data
+ * watchpoint should be 1, 2, 4 or 8 bytes which is aligned to its
+ * length, and an execution breakpoint should be sizeof(long). */
+#[cfg(not(target_arch = "aarch64"))]
+mod arch {
+    use perf_event_open_sys::bindings::{HW_BREAKPOINT_R, HW_BREAKPOINT_RW};
+
+    pub const MAX_BP_LEN: u64 = 8;
+    pub const X_BP_LEN: u64 = std::mem::size_of::<usize>() as u64;
+
+    const HW_BP_LENS: [u64; 4] = [8, 4, 2, 1];
+
+    /* DR0-DR3 are shared by all the kinds of breakpoint */
+    pub fn hw_bp_regs(_bp_type: u32) -> (&'static str, usize) {
+        ("debug registers", 4)
+    }
+
+    /* There is no read-only breakpoint, so the read can only be
+     * watched together with the write. */
+    pub fn legal_kind(bp_type: u32) -> u32 {
+        match bp_type {
+            HW_BREAKPOINT_R => HW_BREAKPOINT_RW,
+            _ => bp_type,
+        }
+    }
+
+    pub fn is_legal_x(_addr: usize, len: u64) -> bool {
+        len == X_BP_LEN
+    }
+
+    /* Any address of the instruction is fine */
+    pub fn legal_x_addr(addr: usize) -> usize {
+        addr
+    }
+
+    pub fn is_legal_data(addr: usize, len: u64) -> bool {
+        HW_BP_LENS.contains(&len) && addr % len as usize == 0
+    }
+
+    pub fn next_bp_len(pos: usize, end: usize) -> u64 {
+        HW_BP_LENS
+            .into_iter()
+            .find(|&sz| pos % sz as usize == 0 && pos + sz as usize <= end)
+            .unwrap_or(1)
+    }
+}
+
+pub fn bp_kind_name(kind: u32) -> &'static str {
+    match kind {
+        HW_BREAKPOINT_R => "r",
+        HW_BREAKPOINT_W => "w",
+        HW_BREAKPOINT_RW => "rw",
+        HW_BREAKPOINT_X => "x",
+        _ => "?",
+    }
+}
+
+/* Choose the length of watchpoint for the object with the size. The
+ * execution breakpoint always has the fixed length. */
+pub fn auto_bp_len(bp_type: u32, size: Option<u64>) -> Option<u64> {
+    if bp_type == HW_BREAKPOINT_X {
+        return Some(arch::X_BP_LEN);
+    }
+
+    size.filter(|&sz| sz > 0)
+}
+
+/* Split the range [addr, addr + len) into the fewest aligned ranges,
+ * each of them can be watched by one hardware breakpoint. It fails if
+ * the range needs more than MAX_WATCHES breakpoints. */
+pub fn split_range(addr: usize, len: u64) -> Result<Vec<(usize, u64)>> {
+    let end = usize::try_from(len)
+        .ok()
+        .and_then(|len| addr.checked_add(len))
+        .ok_or(anyhow!(
+            "{len} bytes at {addr:#x} are out of the address space"
+        ))?;
+    let mut pos = addr;
+    let mut ranges = Vec::new();
+
+    while pos < end {
+        if ranges.len() == MAX_WATCHES {
+            return Err(anyhow!(
+                "{len} bytes at {addr:#x} need more than {MAX_WATCHES} hardware breakpoints"
+            ));
+        }
+
+        let sz = arch::next_bp_len(pos, end);
+        ranges.push((pos, sz));
+        pos += sz as usize;
+    }
+
+    Ok(ranges)
+}
+
+/* Check whether the breakpoint can be armed by the architecture. If
+ * not, suggest the nearest legal layout in the error message. */
+pub fn check_breakpoint(bp_type: u32, addr: usize, len: u64) -> Result<()> {
+    let kind = bp_kind_name(bp_type);
+
+    let legal_type = arch::legal_kind(bp_type);
+    if legal_type != bp_type {
+        let legal_kind = bp_kind_name(legal_type);
+        return Err(anyhow!(
+            "{kind}{len} at {addr:#x} is not supported by the hardware breakpoint, use {legal_kind}{len} at {addr:#x}"
+        ));
+    }
+
+    if bp_type == HW_BREAKPOINT_X {
+        if arch::is_legal_x(addr, len) {
+            return Ok(());
+        }
+
+        let x_len = arch::X_BP_LEN;
+        let x_addr = arch::legal_x_addr(addr);
+        return Err(anyhow!(
+            "{kind}{len} at {addr:#x} is not a legal execution breakpoint, use {kind}{x_len} at {x_addr:#x}"
+        ));
+    }
+
+    if arch::is_legal_data(addr, len) {
+        return Ok(());
+    }
+
+    let layout = split_range(addr, len)?
+        .into_iter()
+        .map(|(addr, len)| format!("{kind}{len} at {addr:#x}"))
+        .collect::<Vec<_>>()
+        .join(" + ");
+    Err(anyhow!(
+        "{kind}{len} at {addr:#x} is not aligned for the hardware breakpoint, use {layout}"
+    ))
+}
+
+/* Suggest the type of watchpoint for the object at the address. The
+ * length is unknown if it is None. The kind is replaced if it can't
+ * be armed on the architecture, e.g. r becomes rw on x86. */
+pub fn suggest_bp_type(bp_type: u32, addr: usize, len: Option<u64>) -> String {
+    let bp_type = arch::legal_kind(bp_type);
+    let kind = bp_kind_name(bp_type);
+
+    if bp_type == HW_BREAKPOINT_X {
+        return format!("{kind}{}", arch::X_BP_LEN);
+    }
+
+    match len {
+        Some(len) if len > arch::MAX_BP_LEN || arch::is_legal_data(addr, len) => {
+            format!("{kind}{len}")
+        }
+        /* It can't be watched by one breakpoint, so suggest the
+         * layout with several watches. */
+        Some(len) if len > 0 => match split_range(addr, len) {
+            Ok(ranges) => ranges
+                .into_iter()
+                .map(|(_, len)| format!("{kind}{len}"))
+                .collect::<Vec<_>>()
+                .join("+"),
+            Err(_) => "-".to_string(),
+        },
+        _ => "-".to_string(),
+    }
+}
+
+pub struct Watch {
+    pub id: u32,
+    pub expr: String,
+    pub bp_type: u32,
+    pub bp_len: u64,
+    pub addr: usize,
+    /* The length is chosen from the size of object, so it is split
+     * whenever one hardware breakpoint can't watch it. */
+    pub auto_len: bool,
+    /* The kexpr root if the address comes from kexpr */
+    pub root: Option<KexprRoot>,
+    /* The CPU whose instance of per-CPU variable is watched */
+    pub percpu: Option<c_int>,
+    /* Only arm the breakpoints on the CPU of the instance */
+    pub pin_cpu: bool,
+}
+
+/* The hardware breakpoint which is armed for a part of the watch */
+pub struct Breakpoint {
+    pub watch: u32,
+    pub part: u32,
+    pub bp_type: u32,
+    pub bp_len: u64,
+    pub addr: usize,
+    /* Only arm on the CPU if it is specified */
+    pub cpu: Option<c_int>,
+}
+
+impl Watch {
+    /* Whether the breakpoint is a part of this watch */
+    pub fn owns(&self, bp: &Breakpoint) -> bool {
+        bp.watch == self.id && bp.cpu == self.percpu.filter(|_| self.pin_cpu)
+    }
+
+    pub fn breakpoints(&self) -> Result<Vec<Breakpoint>> {
+        /* Only split the range which is too long for one hardware
+         * breakpoint. Otherwise, the user wants exactly one breakpoint
+         * and we should tell if it is not possible. */
+        let split = self.auto_len || self.bp_len > arch::MAX_BP_LEN;
+        let ranges = if self.bp_type != HW_BREAKPOINT_X && split {
+            split_range(self.addr, self.bp_len)
+                .map_err(|e| anyhow!("Invalid watch {}: {e}", self.expr))?
+        } else {
+            vec![(self.addr, self.bp_len)]
+        };
+
+        for &(addr, len) in &ranges {
+            check_breakpoint(self.bp_type, addr, len)
+                .map_err(|e| anyhow!("Invalid watch {}: {e}", self.expr))?;
+        }
+
+        let bps = ranges
+            .into_iter()
+            .enumerate()
+            .map(|(part, (addr, bp_len))| Breakpoint {
+                watch: self.id,
+                part: part as u32,
+                bp_type: self.bp_type,
+                bp_len,
+                addr,
+                cpu: self.percpu.filter(|_| self.pin_cpu),
+            })
+            .collect();
+
+        Ok(bps)
+    }
+}
+
+/* Check whether there are enough debug registers for the breakpoints.
+ * All of them are armed together on every CPU or task, except that
+ * the breakpoint pinned to a CPU only takes the registers of the CPU. */
+pub fn check_hw_bps(bps: &[Breakpoint]) -> Result<()> {
+    let mut cpus = bps.iter().map(|bp| bp.cpu).collect::<Vec<_>>();
+    cpus.sort();
+    cpus.dedup();
+
+    for cpu in cpus {
+        let armed = bps
+            .iter()
+            .filter(|bp| bp.cpu.is_none() || bp.cpu == cpu)
+            .collect::<Vec<_>>();
+
+        for bp in &armed {
+            let (regs, max) = arch::hw_bp_regs(bp.bp_type);
+            let used = armed
+                .iter()
+                .filter(|other| arch::hw_bp_regs(other.bp_type).0 == regs)
+                .count();
+            if used > max {
+                let scope = match cpu {
+                    Some(cpu) => format!("CPU {cpu}"),
+                    None => "every CPU or task".to_string(),
+                };
+                return Err(anyhow!(
+                    "{used} hardware breakpoints are needed on {scope}, but there are only {max} {regs}"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl Breakpoint {
+    /* The value of watch_map in BPF program, which should be in
+     * the same layout as watch_t. */
+    pub fn to_map_value(&self) -> Vec<u8> {
+        let mut value = Vec::new();
+        value.extend_from_slice(&self.watch.to_ne_bytes());
+        value.extend_from_slice(&self.part.to_ne_bytes());
+        value.extend_from_slice(&self.bp_type.to_ne_bytes());
+        value.extend_from_slice(&(self.bp_len as u32).to_ne_bytes());
+        value
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+
+    #[test]
+    fn test_check_breakpoint() {
+        assert!(check_breakpoint(HW_BREAKPOINT_RW, 0x1000, 8).is_ok());
+        assert!(check_breakpoint(HW_BREAKPOINT_W, 0x1002, 2).is_ok());
+        assert!(check_breakpoint(HW_BREAKPOINT_X, 0x1003, 8).is_ok());
+
+        let err = check_breakpoint(HW_BREAKPOINT_RW, 0xffc, 8).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("use rw4 at 0xffc + rw4 at 0x1000"));
+        let err = check_breakpoint(HW_BREAKPOINT_X, 0x1000, 4).unwrap_err();
+        assert!(err.to_string().ends_with("use x8 at 0x1000"));
+        let err = check_breakpoint(HW_BREAKPOINT_X, 0x1003, 4).unwrap_err();
+        assert!(err.to_string().ends_with("use x8 at 0x1003"));
+        let err = check_breakpoint(HW_BREAKPOINT_R, 0x1000, 4).unwrap_err();
+        assert!(err.to_string().ends_with("use rw4 at 0x1000"));
+    }
+
+    #[test]
+    fn test_suggest_bp_type() {
+        assert_eq!(suggest_bp_type(HW_BREAKPOINT_RW, 0x1000, Some(8)), "rw8");
+        assert_eq!(suggest_bp_type(HW_BREAKPOINT_R, 0x1000, Some(4)), "rw4");
+        assert_eq!(suggest_bp_type(HW_BREAKPOINT_R, 0x1004, Some(8)), "rw4+rw4");
+        assert_eq!(suggest_bp_type(HW_BREAKPOINT_X, 0x1003, None), "x8");
+        assert_eq!(suggest_bp_type(HW_BREAKPOINT_RW, 0x1000, None), "-");
+    }
+
+    #[test]
+    fn test_check_hw_bps() {
+        let bp = |addr: usize, cpu: Option<c_int>| Breakpoint {
+            watch: 0,
+            part: 0,
+            bp_type: HW_BREAKPOINT_RW,
+            bp_len: 8,
+            addr,
+            cpu,
+        };
+
+        let bps = (0..4).map(|i| bp(0x1000 + i * 8, None)).collect::<Vec<_>>();
+        assert!(check_hw_bps(&bps).is_ok());
+
+        /* rw64 needs 8 breakpoints */
+        let bps = (0..8).map(|i| bp(0x1000 + i * 8, None)).collect::<Vec<_>>();
+        let err = check_hw_bps(&bps).unwrap_err().to_string();
+        assert!(err.starts_with("8 hardware breakpoints are needed on every CPU"));
+
+        /* The instances of per-CPU variable only take one register on
+         * each CPU */
+        let mut bps = (0..8)
+            .map(|i| bp(0x1000 + i * 8, Some(i as c_int)))
+            .collect::<Vec<_>>();
+        bps.extend((0..3).map(|i| bp(0x2000 + i * 8, None)));
+        assert!(check_hw_bps(&bps).is_ok());
+        bps.push(bp(0x3000, Some(2)));
+        let err = check_hw_bps(&bps).unwrap_err().to_string();
+        assert!(err.starts_with("5 hardware breakpoints are needed on CPU 2"));
+    }
+
+    #[test]
+    fn test_parse_percpu() {
+        assert!(matches!(parse_percpu("all"), Ok(PerCpu::All)));
+        assert!(matches!(parse_percpu("3"), Ok(PerCpu::Cpu(3))));
+        assert!(parse_percpu("-1").is_err());
+        assert!(parse_percpu("x").is_err());
+    }
+
+    #[test]
+    fn test_split_range() -> Result<()> {
+        assert_eq!(split_range(0x1000, 8)?, vec![(0x1000, 8)]);
+        assert_eq!(
+            split_range(0x1000, 24)?,
+            vec![(0x1000, 8), (0x1008, 8), (0x1010, 8)]
+        );
+        assert_eq!(split_range(0x1004, 8)?, vec![(0x1004, 4), (0x1008, 4)]);
+        assert_eq!(
+            split_range(0x1003, 7)?,
+            vec![(0x1003, 1), (0x1004, 4), (0x1008, 2)]
+        );
+
+        assert_eq!(
+            split_range(0x1000, 8 * MAX_WATCHES as u64)?.len(),
+            MAX_WATCHES
+        );
+        assert!(split_range(0x1000, 8 * MAX_WATCHES as u64 + 8).is_err());
+        assert!(split_range(0x1000, 99999999999).is_err());
+        assert!(split_range(usize::MAX - 4, 8).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use std::env;
+use std::ffi::CString;
+use std::io::Error;
+
+use anyhow::{anyhow, Result};
+use libc::{c_char, c_int, gid_t, pid_t, uid_t};
+
+/* The command which is run under watch. The child process is forked
+ * first and waits before exec, so the breakpoints can be armed on it
+ * before the command does anything. */
+pub struct Workload {
+    pid: pid_t,
+    go_fd: c_int,
+    exited: bool,
+}
+
+/* The user who runs kmemsnoop by sudo, so the command can be run as
+ * the user instead of root. */
+struct SudoUser {
+    uid: uid_t,
+    gid: gid_t,
+    groups: Vec<gid_t>,
+}
+
+/* Get the supplementary groups of the user, including the gid */
+fn get_groups(name: &str, gid: gid_t) -> Result<Vec<gid_t>> {
+    let cname = CString::new(name)?;
+    let mut groups: Vec<gid_t> = vec![0; 64];
+    loop {
+        let mut ngroups = groups.len() as c_int;
+        let ret =
+            unsafe { libc::getgrouplist(cname.as_ptr(), gid, groups.as_mut_ptr(), &mut ngroups) };
+        if ret >= 0 {
+            groups.truncate(ngroups as usize);
+            return Ok(groups);
+        }
+
+        /* The ngroups is updated to the number of groups if the
+         * buffer is too small. */
+        if ngroups as usize <= groups.len() {
+            return Err(anyhow!("Failed to get groups of user {name}"));
+        }
+        groups.resize(ngroups as usize, 0);
+    }
+}
+
+fn sudo_user() -> Result<Option<SudoUser>> {
+    let (Ok(uid), Ok(gid)) = (env::var("SUDO_UID"), env::var("SUDO_GID")) else {
+        return Ok(None);
+    };
+
+    let uid = uid
+        .parse::<uid_t>()
+        .map_err(|e| anyhow!("Invalid SUDO_UID {uid}: {e}"))?;
+    let gid = gid
+        .parse::<gid_t>()
+        .map_err(|e| anyhow!("Invalid SUDO_GID {gid}: {e}"))?;
+    let groups = match env::var("SUDO_USER") {
+        Ok(name) => get_groups(&name, gid)?,
+        Err(_) => vec![gid],
+    };
+
+    Ok(Some(SudoUser { uid, gid, groups }))
+}
+
+impl Workload {
+    /* Fork the child for the command. It is run as root unless
+     * as_user is set, then it is run as the user who invokes sudo. */
+    pub fn prepare(cmd: &[String], as_user: bool) -> Result<Self> {
+        /* Everything should be prepared before fork(), because we
+         * can't allocate memory in the child. */
+        let args = cmd
+            .iter()
+            .map(|arg| CString::new(arg.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut argv: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+        argv.push(std::ptr::null());
+        let user = match as_user {
+            true => Some(sudo_user()?.ok_or(anyhow!(
+                "Can't run the command as the user, kmemsnoop is not run by sudo"
+            ))?),
+            false => None,
+        };
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(anyhow!("Failed to create pipe: {}", Error::last_os_error()));
+        }
+        let (rfd, wfd) = (fds[0], fds[1]);
+
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(anyhow!("Failed to fork: {}", Error::last_os_error()));
+        }
+
+        if pid == 0 {
+            /* Wait until the parent is ready. If the parent is gone
+             * without the start signal, don't run the command. */
+            unsafe {
+                libc::close(wfd);
+                let mut buf = 0u8;
+                if libc::read(rfd, &mut buf as *mut u8 as *mut _, 1) != 1 {
+                    libc::_exit(127);
+                }
+
+                /* kmemsnoop is escalated by sudo, but the command
+                 * is asked to be run as the user. The groups must be
+                 * changed before the user. */
+                if let Some(user) = &user {
+                    if libc::setgroups(user.groups.len(), user.groups.as_ptr()) != 0
+                        || libc::setgid(user.gid) != 0
+                        || libc::setuid(user.uid) != 0
+                    {
+                        libc::_exit(127);
+                    }
+                }
+
+                libc::execvp(argv[0], argv.as_ptr());
+                libc::_exit(127);
+            }
+        }
+
+        unsafe { libc::close(rfd) };
+
+        Ok(Workload {
+            pid,
+            go_fd: wfd,
+            exited: false,
+        })
+    }
+
+    pub fn pid(&self) -> pid_t {
+        self.pid
+    }
+
+    /* Let the child process exec the command */
+    pub fn start(&mut self) -> Result<()> {
+        let buf = 1u8;
+        let ret = unsafe { libc::write(self.go_fd, &buf as *const u8 as *const _, 1) };
+        let err = Error::last_os_error();
+        unsafe { libc::close(self.go_fd) };
+        self.go_fd = -1;
+
+        if ret != 1 {
+            return Err(anyhow!("Failed to start workload: {err}"));
+        }
+
+        Ok(())
+    }
+
+    /* Return the exit status of the command if it is finished. The
+     * status is 128 + signal number like shell if it is killed. */
+    pub fn try_wait(&mut self) -> Result<Option<u8>> {
+        if self.exited {
+            return Err(anyhow!("Workload {} has been reaped", self.pid));
+        }
+
+        let mut status = 0;
+        let ret = unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) };
+        if ret < 0 {
+            return Err(anyhow!(
+                "Failed to wait workload: {}",
+                Error::last_os_error()
+            ));
+        }
+
+        if ret == 0 {
+            return Ok(None);
+        }
+
+        self.exited = true;
+        if libc::WIFEXITED(status) {
+            return Ok(Some(libc::WEXITSTATUS(status) as u8));
+        }
+
+        Ok(Some((128 + libc::WTERMSIG(status)) as u8))
+    }
+
+    /* Terminate the command if it is still running */
+    pub fn terminate(&mut self) {
+        if self.exited {
+            return;
+        }
+
+        unsafe {
+            libc::kill(self.pid, libc::SIGTERM);
+            libc::waitpid(self.pid, std::ptr::null_mut(), 0);
+        }
+        self.exited = true;
+    }
+}
+
+impl Drop for Workload {
+    fn drop(&mut self) {
+        if self.go_fd >= 0 {
+            unsafe { libc::close(self.go_fd) };
+        }
+
+        self.terminate();
+    }
+}
@@ -1,6 +1,7 @@
 #!/usr/bin/env drgn
 
 import argparse
+import os
 
 import drgn
 from drgn.helpers.common import *
@@ -15,12 +16,28 @@ def get_args():
     parser.add_argument("--pci_dev", type=str, help="name of the pci device")
     parser.add_argument("--usb_dev", type=str, help="name of the usb device")
     parser.add_argument("--plat_dev", type=str, help="name of the platform device")
+    parser.add_argument("--global", dest="glob", type=str, help="name of the global variable")
+    parser.add_argument("--file", type=str, help="pid:fd of the opened file")
+    parser.add_argument("--sock", type=str, help="pid:fd of the socket")
+    parser.add_argument("--inode", type=str, help="path of the inode")
+    parser.add_argument("--sb", type=str, help="path on the superblock")
+    parser.add_argument("--module", type=str, help="name of the module")
+    parser.add_argument("--irq", type=int, help="IRQ number of the irq_desc")
+    parser.add_argument("--net_dev", type=str, help="name of the network device")
+    parser.add_argument("--netns_pid", type=int, help="pid in the network namespace")
+    parser.add_argument("--cpu", type=int, help="CPU of the per-CPU global variable")
+    parser.add_argument("--size", action="store_true", help="print the size of object instead")
     parser.add_argument("kexpr")
     args = parser.parse_args()
     return args
 
 def parse_kexpr(obj, kexpr):
-    if kexpr[0] == "&":
+    if size:
+        if kexpr[0] == "&":
+            print(hex(drgn.sizeof(eval(f"obj.{kexpr[1:]}"))))
+        else:
+            print(hex(drgn.sizeof(eval(f"obj.{kexpr}[0]"))))
+    elif kexpr[0] == "&":
         print(eval(f"hex(obj.{kexpr[1:]}.address_)"))
     else:
         print(eval(f"hex(obj.{kexpr})"))
@@ -40,12 +57,74 @@ def busdev_kexpr2addr(bus, device, kexpr):
     dev = drgn_utils.to_subsys_dev(bus, dev)
     parse_kexpr(dev, kexpr)
 
+def global_kexpr2addr(sym, kexpr, cpu):
+    var = prog[sym]
+    if cpu is not None:
+        var = per_cpu(var, cpu)
+    parse_kexpr(var, kexpr)
+
+def file_kexpr2addr(task_fd, kexpr, sock=False):
+    pid, fd = [int(x) for x in task_fd.split(":")]
+    task = find_task(pid)
+    if not task:
+        exit(f"Can find 'struct task_struct' for pid={pid}")
+
+    file = fget(task, fd)
+    if not file:
+        exit(f"Can find 'struct file' for {task_fd}")
+
+    if sock:
+        file = drgn.cast("struct socket *", file.private_data)
+    parse_kexpr(file, kexpr)
+
+def path_kexpr2addr(path, kexpr, sb=False):
+    inode = path_lookup(prog, os.path.abspath(path)).dentry.d_inode
+    if sb:
+        inode = inode.i_sb
+    parse_kexpr(inode, kexpr)
+
+def module_kexpr2addr(name, kexpr):
+    module = find_module(prog, name)
+    if not module:
+        exit(f"Can find 'struct module' for {name}")
+
+    parse_kexpr(module, kexpr)
+
+def irq_kexpr2addr(irq, kexpr):
+    desc = irq_to_desc(prog, irq)
+    if not desc:
+        exit(f"Can find 'struct irq_desc' for irq={irq}")
+
+    parse_kexpr(desc, kexpr)
+
+def netdev_kexpr2addr(name, netns_pid, kexpr):
+    net = prog["init_net"].address_of_()
+    if netns_pid:
+        net = find_task(netns_pid).nsproxy.net_ns
+
+    dev = netdev_get_by_name(net, name)
+    if not dev:
+        exit(f"Can find 'struct net_device' for {name}")
+
+    parse_kexpr(dev, kexpr)
+
 args = get_args()
 pid = args.pid
 pci_dev = args.pci_dev
 usb_dev = args.usb_dev
 plat_dev = args.plat_dev
+glob = args.glob
+file = args.file
+sock = args.sock
+inode = args.inode
+sb = args.sb
+module = args.module
+irq = args.irq
+net_dev = args.net_dev
+netns_pid = args.netns_pid
 kexpr = args.kexpr
+size = args.size
+cpu = args.cpu
 
 
 # If multiple kexpr is specified, only one
@@ -58,6 +137,22 @@ elif usb_dev:
     busdev_kexpr2addr("usb", usb_dev, kexpr)
 elif plat_dev:
     busdev_kexpr2addr("platform", plat_dev, kexpr)
+elif glob:
+    global_kexpr2addr(glob, kexpr, cpu)
+elif file:
+    file_kexpr2addr(file, kexpr)
+elif sock:
+    file_kexpr2addr(sock, kexpr, sock=True)
+elif inode:
+    path_kexpr2addr(inode, kexpr)
+elif sb:
+    path_kexpr2addr(sb, kexpr, sb=True)
+elif module:
+    module_kexpr2addr(module, kexpr)
+elif irq is not None:
+    irq_kexpr2addr(irq, kexpr)
+elif net_dev:
+    netdev_kexpr2addr(net_dev, netns_pid, kexpr)
 else:
     print(f"Invalid arguments {args}")
     exit(1)